@@ -0,0 +1,134 @@
+/* Copyright 2022 Pierrick MARIE
+
+This file is part of rust-discovery
+
+LCS is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+Rust-discovery is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with rust-discovery.  If not, see <http://www.gnu.org/licenses/>. */
+
+/*
+Responsiveness of the server while large files are transferred: run with `cargo bench --bench noop_latency`.
+
+The server is started with a temporary configuration. Some sessions download and upload a large file in a loop,
+while 100 other sessions send NOOP and measure how long the reply takes. The benchmark fails if a NOOP waits longer
+than MAX_LATENCY: a blocking file system call would stall every session handled by the same worker.
+*/
+
+use std::io::{self, Error};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use tokio::io::AsyncWriteExt;
+
+#[path = "../tests/common/mod.rs"]
+mod common;
+
+use common::{Server, Session, BIG_FILE};
+
+const NOOP_SESSIONS: usize = 100;
+const DOWNLOAD_SESSIONS: usize = 4;
+const UPLOAD_SESSIONS: usize = 4;
+const FILE_SIZE: usize = 64 * 1024 * 1024;
+const DURATION: Duration = Duration::from_secs(10);
+const NOOP_INTERVAL: Duration = Duration::from_millis(50);
+const MAX_LATENCY: Duration = Duration::from_secs(1);
+
+/// Download the large file until the end of the benchmark, returns the number of bytes received
+async fn download(port: u16, deadline: Instant, content: Arc<Vec<u8>>) -> io::Result<u64> {
+	let mut session = Session::login(port).await?;
+	let mut total = 0;
+	while Instant::now() < deadline {
+		total += session.retr(BIG_FILE, 0, content.as_slice()).await?;
+	}
+	Ok(total)
+}
+
+/// Upload a large file until the end of the benchmark, returns the number of bytes sent
+async fn upload(port: u16, deadline: Instant, id: usize) -> io::Result<u64> {
+	let mut session = Session::login(port).await?;
+	let buffer = vec![b'x'; 64 * 1024];
+	let mut total = 0;
+	while Instant::now() < deadline {
+		let mut data = session.epsv().await?;
+		session.expect(format!("STOR upload-{}.dat", id).as_str(), "150").await?;
+		for _ in 0..FILE_SIZE / buffer.len() {
+			data.write_all(buffer.as_slice()).await?;
+			total += buffer.len() as u64;
+		}
+		data.shutdown().await?;
+		drop(data);
+		session.reply().await?;
+	}
+	Ok(total)
+}
+
+/// Send NOOP at a regular interval until the end of the benchmark, returns the time taken by each reply
+async fn noop(port: u16, deadline: Instant) -> io::Result<Vec<Duration>> {
+	let mut session = Session::login(port).await?;
+	let mut latencies = vec![];
+	while Instant::now() < deadline {
+		tokio::time::sleep(NOOP_INTERVAL).await;
+		let start = Instant::now();
+		session.expect("NOOP", "200").await?;
+		latencies.push(start.elapsed());
+	}
+	session.command("QUIT").await?;
+	Ok(latencies)
+}
+
+async fn run() -> io::Result<bool> {
+	let config = format!("max_clients = {}", NOOP_SESSIONS + DOWNLOAD_SESSIONS + UPLOAD_SESSIONS + 10);
+	let server = Server::start("noop-latency", FILE_SIZE, config.as_str()).await?;
+	let port = server.port;
+	let deadline = Instant::now() + DURATION;
+
+	let noops: Vec<_> = (0..NOOP_SESSIONS).map(|_| tokio::spawn(noop(port, deadline))).collect();
+	let mut transfers: Vec<_> = (0..DOWNLOAD_SESSIONS).map(|_| tokio::spawn(download(port, deadline, server.content.clone()))).collect();
+	transfers.extend((0..UPLOAD_SESSIONS).map(|id| tokio::spawn(upload(port, deadline, id))));
+
+	let mut latencies = vec![];
+	for task in noops {
+		latencies.extend(task.await.map_err(Error::other)??);
+	}
+	let mut bytes = 0;
+	for task in transfers {
+		bytes += task.await.map_err(Error::other)??;
+	}
+	let elapsed = DURATION.as_secs_f64();
+
+	if latencies.is_empty() {
+		return Err(Error::other("No NOOP reply"));
+	}
+	latencies.sort();
+	let percentile = |p: usize| latencies[(latencies.len() - 1) * p / 100];
+	let max = *latencies.last().unwrap_or(&Duration::ZERO);
+	println!("{} NOOP sessions, {} downloads and {} uploads of {} MiB during {:?}",
+			 NOOP_SESSIONS, DOWNLOAD_SESSIONS, UPLOAD_SESSIONS, FILE_SIZE / 1024 / 1024, DURATION);
+	println!("transferred: {:.1} MiB/s", bytes as f64 / elapsed / 1024.0 / 1024.0);
+	println!("NOOP replies: {}, median {:?}, p99 {:?}, max {:?}", latencies.len(), percentile(50), percentile(99), max);
+	Ok(max <= MAX_LATENCY)
+}
+
+#[tokio::main]
+async fn main() {
+	match run().await {
+		Ok(true) => {}
+		Ok(false) => {
+			eprintln!("A NOOP waited longer than {:?}", MAX_LATENCY);
+			std::process::exit(1);
+		}
+		Err(e) => {
+			eprintln!("Benchmark failed: {}", e);
+			std::process::exit(1);
+		}
+	}
+}
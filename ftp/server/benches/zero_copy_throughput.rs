@@ -0,0 +1,118 @@
+/* Copyright 2022 Pierrick MARIE
+
+This file is part of rust-discovery
+
+LCS is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+Rust-discovery is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with rust-discovery.  If not, see <http://www.gnu.org/licenses/>. */
+
+/*
+Downloads in Image mode with sendfile(2) compared to the buffered path: run with `cargo bench --bench zero_copy_throughput`.
+
+The same downloads are done by a server started with zero_copy = false and by one started with zero_copy = true,
+everything else is the same. Each of them runs ROUNDS times, in turns, and the best round is kept.
+For each of them the throughput seen by the clients and the CPU time used by the server are printed.
+Before the measure, a download from the start and one after REST are compared with the file: the benchmark fails if
+a byte differs. The measured downloads only count the bytes, so checking them does not hide the cost of the server.
+*/
+
+use std::io::{self, Error};
+use std::time::{Duration, Instant};
+
+#[path = "../tests/common/mod.rs"]
+mod common;
+
+use common::{Server, Session, BIG_FILE};
+
+const SESSIONS: usize = 4;
+const DOWNLOADS: usize = 4; // By session
+const FILE_SIZE: usize = 128 * 1024 * 1024;
+const RESTART_OFFSET: u64 = 50_000_017; // Not aligned on a chunk sent by sendfile
+const ROUNDS: usize = 2;
+
+struct Measure {
+	throughput: f64, // MiB/s
+	cpu_time: Option<Duration>,
+}
+
+async fn download(port: u16) -> io::Result<u64> {
+	let mut session = Session::login(port).await?;
+	let mut total = 0;
+	for _ in 0..DOWNLOADS {
+		total += session.retr_discard(BIG_FILE).await?;
+	}
+	session.command("QUIT").await?;
+	Ok(total)
+}
+
+async fn measure(zero_copy: bool) -> io::Result<Measure> {
+	let server = Server::start("zero-copy", FILE_SIZE, format!("zero_copy = {}", zero_copy).as_str()).await?;
+	// Read the file once, so both runs find it in the page cache, and check a restarted download
+	let mut session = Session::login(server.port).await?;
+	session.retr(BIG_FILE, 0, server.content.as_slice()).await?;
+	session.retr(BIG_FILE, RESTART_OFFSET, server.content.as_slice()).await?;
+	session.command("QUIT").await?;
+	let cpu_before = server.cpu_time();
+
+	let start = Instant::now();
+	let sessions: Vec<_> = (0..SESSIONS).map(|_| tokio::spawn(download(server.port))).collect();
+	let mut bytes = 0;
+	for task in sessions {
+		bytes += task.await.map_err(Error::other)??;
+	}
+	let elapsed = start.elapsed();
+
+	if bytes != (SESSIONS * DOWNLOADS * FILE_SIZE) as u64 {
+		return Err(Error::other(format!("{} bytes received", bytes)));
+	}
+	Ok(Measure {
+		throughput: bytes as f64 / elapsed.as_secs_f64() / 1024.0 / 1024.0,
+		cpu_time: cpu_before.zip(server.cpu_time()).map(|(before, after)| after.saturating_sub(before)),
+	})
+}
+
+fn best(first: Measure, second: Measure) -> Measure {
+	if second.throughput > first.throughput {
+		second
+	} else {
+		first
+	}
+}
+
+fn print(name: &str, measure: &Measure) {
+	match measure.cpu_time {
+		Some(cpu_time) => println!("{:>9}: {:8.1} MiB/s, server CPU time {:?}", name, measure.throughput, cpu_time),
+		None => println!("{:>9}: {:8.1} MiB/s", name, measure.throughput),
+	}
+}
+
+async fn run() -> io::Result<()> {
+	println!("{} sessions, {} downloads of {} MiB each", SESSIONS, DOWNLOADS, FILE_SIZE / 1024 / 1024);
+	let mut buffered = measure(false).await?;
+	let mut zero_copy = measure(true).await?;
+	for _ in 1..ROUNDS {
+		buffered = best(buffered, measure(false).await?);
+		zero_copy = best(zero_copy, measure(true).await?);
+	}
+	print("buffered", &buffered);
+	print("sendfile", &zero_copy);
+	println!("{:>9}: x{:.2}", "speedup", zero_copy.throughput / buffered.throughput);
+	Ok(())
+}
+
+#[tokio::main]
+async fn main() {
+	if let Err(e) = run().await {
+		eprintln!("Benchmark failed: {}", e);
+		std::process::exit(1);
+	}
+}
@@ -0,0 +1,216 @@
+/* Copyright 2022 Pierrick MARIE
+
+This file is part of rust-discovery
+
+LCS is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+Rust-discovery is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with rust-discovery.  If not, see <http://www.gnu.org/licenses/>. */
+
+use std::net::IpAddr;
+
+use serde::Deserialize;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Action {
+	Allow,
+	Deny,
+}
+
+/**
+ * Network in CIDR notation: "192.168.0.0/16", "fd00::/8". A single address has no prefix length: "10.1.2.3".
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Network {
+	address: IpAddr,
+	prefix: u8, // Number of bits of the address which are compared
+}
+
+impl Network {
+	/**
+	 * True if ip is in the network. IPv4 addresses mapped to IPv6 ("::ffff:a.b.c.d") are IPv4 addresses.
+	 */
+	pub fn contains(&self, ip: IpAddr) -> bool {
+		match (self.address, ip.to_canonical()) {
+			(IpAddr::V4(network), IpAddr::V4(ip)) => {
+				let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+				u32::from(network) & mask == u32::from(ip) & mask
+			}
+			(IpAddr::V6(network), IpAddr::V6(ip)) => {
+				let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+				u128::from(network) & mask == u128::from(ip) & mask
+			}
+			_ => false,
+		}
+	}
+}
+
+impl TryFrom<&str> for Network {
+	type Error = String;
+
+	fn try_from(network: &str) -> Result<Self, Self::Error> {
+		let (address, prefix) = match network.split_once('/') {
+			Some((address, prefix)) => (address, Some(prefix)),
+			None => (network, None),
+		};
+		let address = address.parse::<IpAddr>().map_err(|_| format!("invalid address in network '{}'", network))?;
+		let max = if address.is_ipv4() { 32 } else { 128 };
+		let prefix = match prefix {
+			Some(prefix) => prefix.parse::<u8>().ok().filter(|prefix| *prefix <= max)
+				.ok_or_else(|| format!("invalid prefix length in network '{}'", network))?,
+			None => max,
+		};
+		Ok(Network { address, prefix })
+	}
+}
+
+/**
+ * An access rule of the configuration: "allow <network>" or "deny <network>"
+ */
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct AccessRule {
+	pub action: Action,
+	pub network: Network,
+}
+
+impl TryFrom<String> for AccessRule {
+	type Error = String;
+
+	fn try_from(rule: String) -> Result<Self, Self::Error> {
+		let (action, network) = rule.trim().split_once(' ')
+			.ok_or_else(|| format!("access rule '{}' is not 'allow <network>' or 'deny <network>'", rule))?;
+		let action = match action {
+			"allow" => Action::Allow,
+			"deny" => Action::Deny,
+			_ => return Err(format!("access rule '{}' must start with allow or deny", rule)),
+		};
+		Ok(AccessRule {
+			action,
+			network: Network::try_from(network.trim())?,
+		})
+	}
+}
+
+/**
+ * The first rule whose network contains ip applies. An address which matches no rule is allowed.
+ */
+pub fn is_allowed(rules: &[AccessRule], ip: IpAddr) -> bool {
+	match rules.iter().find(|rule| rule.network.contains(ip)) {
+		Some(rule) => rule.action == Action::Allow,
+		None => true,
+	}
+}
+
+/**
+ * Whether PORT or EPRT sent by client may open a data connection to target.
+ * Without rules, only the address of the client is allowed: the server cannot be used to send data to another host
+ * (FTP bounce attack).
+ */
+pub fn is_data_target_allowed(rules: &[AccessRule], client: IpAddr, target: IpAddr) -> bool {
+	if rules.is_empty() {
+		target.to_canonical() == client.to_canonical()
+	} else {
+		is_allowed(rules, target)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn ip(address: &str) -> IpAddr {
+		address.parse().unwrap()
+	}
+
+	fn rules(rules: &[&str]) -> Vec<AccessRule> {
+		rules.iter().map(|rule| AccessRule::try_from(rule.to_string()).unwrap()).collect()
+	}
+
+	#[test]
+	fn network_contains() {
+		let network = Network::try_from("192.168.0.0/16").unwrap();
+		assert!(network.contains(ip("192.168.10.1")));
+		assert!(!network.contains(ip("192.169.0.1")));
+		assert!(network.contains(ip("::ffff:192.168.1.1")));
+		assert!(!network.contains(ip("fd00::1")));
+
+		let single = Network::try_from("10.1.2.3").unwrap();
+		assert!(single.contains(ip("10.1.2.3")));
+		assert!(!single.contains(ip("10.1.2.4")));
+
+		let v6 = Network::try_from("fd00::/8").unwrap();
+		assert!(v6.contains(ip("fdff::1")));
+		assert!(!v6.contains(ip("fe80::1")));
+	}
+
+	#[test]
+	fn prefix_zero_contains_every_address_of_its_family() {
+		assert!(Network::try_from("0.0.0.0/0").unwrap().contains(ip("203.0.113.9")));
+		assert!(!Network::try_from("0.0.0.0/0").unwrap().contains(ip("2001:db8::1")));
+		assert!(Network::try_from("::/0").unwrap().contains(ip("2001:db8::1")));
+	}
+
+	#[test]
+	fn invalid_networks() {
+		assert!(Network::try_from("192.168.0.0/33").is_err());
+		assert!(Network::try_from("fd00::/129").is_err());
+		assert!(Network::try_from("192.168.0.0/").is_err());
+		assert!(Network::try_from("192.168.0/16").is_err());
+	}
+
+	#[test]
+	fn parse_rules() {
+		let rule = AccessRule::try_from("  deny   10.0.0.0/8 ".to_string()).unwrap();
+		assert_eq!(rule.action, Action::Deny);
+		assert_eq!(rule.network, Network::try_from("10.0.0.0/8").unwrap());
+		assert!(AccessRule::try_from("allow".to_string()).is_err());
+		assert!(AccessRule::try_from("permit 10.0.0.0/8".to_string()).is_err());
+		assert!(AccessRule::try_from("allow 10.0.0.0/40".to_string()).is_err());
+	}
+
+	#[test]
+	fn rules_in_the_configuration() {
+		#[derive(Deserialize)]
+		struct Config {
+			client_rules: Vec<AccessRule>,
+		}
+		let config: Config = toml::from_str("client_rules = [\"allow 127.0.0.1\", \"deny 0.0.0.0/0\"]").unwrap();
+		assert_eq!(config.client_rules, rules(&["allow 127.0.0.1", "deny 0.0.0.0/0"]));
+		let error = toml::from_str::<Config>("client_rules = [\"allow 127.0.0.1/99\"]").err().unwrap();
+		assert!(error.to_string().contains("invalid prefix length in network '127.0.0.1/99'"), "{}", error);
+	}
+
+	#[test]
+	fn first_matching_rule_applies() {
+		let rules = rules(&["allow 192.168.1.10", "deny 192.168.0.0/16", "allow ::1"]);
+		assert!(is_allowed(&rules, ip("192.168.1.10")));
+		assert!(!is_allowed(&rules, ip("192.168.1.11")));
+		assert!(is_allowed(&rules, ip("10.0.0.1")));
+		assert!(is_allowed(&[], ip("10.0.0.1")));
+	}
+
+	#[test]
+	fn data_connections_go_to_the_client_by_default() {
+		let client = ip("192.168.1.10");
+		assert!(is_data_target_allowed(&[], client, ip("192.168.1.10")));
+		assert!(is_data_target_allowed(&[], client, ip("::ffff:192.168.1.10")));
+		assert!(!is_data_target_allowed(&[], client, ip("192.168.1.11")));
+		assert!(!is_data_target_allowed(&[], ip("::1"), ip("127.0.0.1")));
+
+		// With rules, the rules decide, the address of the client included
+		let any = rules(&["allow 0.0.0.0/0", "allow ::/0"]);
+		assert!(is_data_target_allowed(&any, client, ip("203.0.113.9")));
+		let lan = rules(&["deny 192.168.1.10"]);
+		assert!(!is_data_target_allowed(&lan, client, client));
+		assert!(is_data_target_allowed(&lan, client, ip("10.0.0.1")));
+	}
+}
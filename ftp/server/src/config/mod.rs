@@ -0,0 +1,280 @@
+/* Copyright 2022 Pierrick MARIE
+
+This file is part of rust-discovery
+
+LCS is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+Rust-discovery is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with rust-discovery.  If not, see <http://www.gnu.org/licenses/>. */
+
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::{Path, PathBuf};
+
+use clap::Parser;
+use log::LevelFilter;
+use serde::Deserialize;
+
+use crate::config::access::AccessRule;
+
+pub mod access;
+
+pub const DEFAULT_CONFIG_FILE: &str = "ftp-server.toml";
+
+/**
+ * Command line arguments. They take precedence over the configuration file.
+ */
+#[derive(Parser, Debug)]
+#[command(name = "ftp-server", version, about = "FTP server written in Rust", long_about = None)]
+pub struct Args {
+	/// Configuration file [default: ftp-server.toml, if it exists]
+	#[arg(short, long)]
+	pub config: Option<PathBuf>,
+
+	/// Address to listen on, may be repeated
+	#[arg(short, long = "listen")]
+	pub listen: Vec<IpAddr>,
+
+	/// Port of the control connection
+	#[arg(short, long)]
+	pub port: Option<u16>,
+
+	/// Log level: off, error, warn, info, debug or trace
+	#[arg(long)]
+	pub log_level: Option<LevelFilter>,
+
+	/// File declaring the virtual users
+	#[arg(short, long)]
+	pub users_file: Option<PathBuf>,
+}
+
+/**
+ * What to do with the file of an upload which did not complete (ABOR or lost data connection)
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PartialUploads {
+	Keep, // The client can resume the upload with REST and STOR
+	Delete, // Remove what was received: the file if it was created by the upload, the appended bytes otherwise
+}
+
+/**
+ * Where the files of the users are stored
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageBackend {
+	Local, // The root directory of each user on the local file system
+	Memory, // An empty tree in memory for each user, lost when the server stops
+}
+
+/**
+ * Server configuration, read from a TOML file. Every missing value takes its default value.
+ */
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+	pub listen_addresses: Vec<IpAddr>,
+	pub port: u16,
+	pub passive_address: Option<Ipv4Addr>, // Sent in PASV replies when the server is behind a NAT
+	pub passive_ports: Option<[u16; 2]>, // First and last port of the passive data connections
+	pub idle_timeout: u64, // Seconds, control connection
+	pub data_timeout: u64, // Seconds, data connections
+	pub log_level: LevelFilter,
+	pub banner: String,
+	pub max_clients: usize,
+	pub max_clients_per_ip: Option<usize>, // Sessions from a single address, None for no limit
+	pub max_logins_per_user: Option<usize>, // Sessions logged in as a single user, None for no limit
+	pub login_failure_delay: u64, // Seconds before the reply to a failed PASS, doubled after each failure
+	pub max_login_failures: u32, // Failed logins before the address is banned, 0 to never ban
+	pub ban_duration: u64, // Seconds
+	pub client_rules: Vec<AccessRule>, // Networks allowed to connect, the first matching rule applies
+	pub port_rules: Vec<AccessRule>, // Networks PORT and EPRT may connect to, the first matching rule applies. Only the client if empty
+	pub users_file: PathBuf,
+	pub password_file: Option<PathBuf>, // Takes precedence over the hashes of the users file
+	pub tls_certificate: Option<PathBuf>,
+	pub tls_private_key: Option<PathBuf>,
+	pub require_tls: bool, // Refuse USER until the control connection is protected, and transfers until PROT P
+	pub partial_uploads: PartialUploads,
+	pub storage: StorageBackend,
+	pub zero_copy: bool, // Send the files with sendfile(2) for the downloads in Image mode without TLS (Linux only)
+	// Bandwidth limits of the data connections in bytes per second, None for no limit
+	pub session_download_limit: Option<u64>,
+	pub session_upload_limit: Option<u64>,
+	pub user_download_limit: Option<u64>, // Shared by all the sessions of a user
+	pub user_upload_limit: Option<u64>,
+	pub server_download_limit: Option<u64>, // Shared by all the sessions
+	pub server_upload_limit: Option<u64>,
+}
+
+impl Default for Config {
+	fn default() -> Self {
+		Config {
+			listen_addresses: vec![IpAddr::V4(Ipv4Addr::LOCALHOST), IpAddr::V6(Ipv6Addr::LOCALHOST)],
+			port: 8080,
+			passive_address: None,
+			passive_ports: None,
+			idle_timeout: 300,
+			data_timeout: 300,
+			log_level: LevelFilter::Info,
+			banner: "Welcome to my rust ftp server. I'm waiting for your user name".to_string(),
+			max_clients: 100,
+			max_clients_per_ip: None,
+			max_logins_per_user: None,
+			login_failure_delay: 1,
+			max_login_failures: 5,
+			ban_duration: 900,
+			client_rules: vec![],
+			port_rules: vec![],
+			users_file: PathBuf::from("users.toml"),
+			password_file: None,
+			tls_certificate: None,
+			tls_private_key: None,
+			require_tls: false,
+			partial_uploads: PartialUploads::Keep,
+			storage: StorageBackend::Local,
+			zero_copy: true,
+			session_download_limit: None,
+			session_upload_limit: None,
+			user_download_limit: None,
+			user_upload_limit: None,
+			server_download_limit: None,
+			server_upload_limit: None,
+		}
+	}
+}
+
+impl Config {
+	/**
+	 * Read the configuration file given on the command line (or the default one), then apply the command line arguments.
+	 */
+	pub fn load(args: Args) -> std::io::Result<Self> {
+		let mut config = match args.config {
+			Some(path) => Config::from_file(path.as_path())?,
+			None => {
+				if Path::new(DEFAULT_CONFIG_FILE).exists() {
+					Config::from_file(Path::new(DEFAULT_CONFIG_FILE))?
+				} else {
+					Config::default()
+				}
+			}
+		};
+
+		if !args.listen.is_empty() {
+			config.listen_addresses = args.listen;
+		}
+		if let Some(port) = args.port {
+			config.port = port;
+		}
+		if let Some(log_level) = args.log_level {
+			config.log_level = log_level;
+		}
+		if let Some(users_file) = args.users_file {
+			config.users_file = users_file;
+		}
+
+		config.check()?;
+		Ok(config)
+	}
+
+	fn from_file(path: &Path) -> std::io::Result<Self> {
+		toml::from_str(fs::read_to_string(path)?.as_str())
+			.map_err(|e| Error::new(ErrorKind::InvalidData, format!("{:?}: {}", path, e)))
+	}
+
+	fn check(&self) -> std::io::Result<()> {
+		if self.listen_addresses.is_empty() {
+			return Err(Error::new(ErrorKind::InvalidInput, "listen_addresses is empty"));
+		}
+		if let Some([first, last]) = self.passive_ports {
+			if first == 0 || first > last {
+				return Err(Error::new(ErrorKind::InvalidInput, "passive_ports must be [first, last] with 0 < first <= last"));
+			}
+		}
+		if self.tls_certificate.is_some() != self.tls_private_key.is_some() {
+			return Err(Error::new(ErrorKind::InvalidInput, "tls_certificate and tls_private_key go together"));
+		}
+		if self.require_tls && self.tls_certificate.is_none() {
+			return Err(Error::new(ErrorKind::InvalidInput, "require_tls needs tls_certificate and tls_private_key"));
+		}
+		if self.max_clients_per_ip == Some(0) || self.max_logins_per_user == Some(0) {
+			return Err(Error::new(ErrorKind::InvalidInput, "max_clients_per_ip and max_logins_per_user must be greater than 0"));
+		}
+		let limits = [self.session_download_limit, self.session_upload_limit, self.user_download_limit,
+			self.user_upload_limit, self.server_download_limit, self.server_upload_limit];
+		if limits.contains(&Some(0)) {
+			return Err(Error::new(ErrorKind::InvalidInput, "bandwidth limits must be greater than 0, remove them for no limit"));
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn config(content: &str) -> Config {
+		toml::from_str(content).unwrap()
+	}
+
+	fn check_error(content: &str) -> String {
+		config(content).check().err().map(|e| e.to_string()).unwrap_or_default()
+	}
+
+	#[test]
+	fn default_configuration_is_valid() {
+		assert!(Config::default().check().is_ok());
+		assert!(config("port = 2121\npassive_ports = [40000, 40000]\nmax_logins_per_user = 1").check().is_ok());
+	}
+
+	#[test]
+	fn check_refuses_inconsistent_values() {
+		assert!(check_error("listen_addresses = []").contains("listen_addresses"));
+		assert!(check_error("passive_ports = [0, 10]").contains("passive_ports"));
+		assert!(check_error("passive_ports = [40010, 40000]").contains("passive_ports"));
+		assert!(check_error("tls_certificate = \"cert.pem\"").contains("go together"));
+		assert!(check_error("require_tls = true").contains("require_tls"));
+		assert!(check_error("max_clients_per_ip = 0").contains("greater than 0"));
+		assert!(check_error("max_logins_per_user = 0").contains("greater than 0"));
+		assert!(check_error("user_upload_limit = 0").contains("bandwidth limits"));
+	}
+
+	#[test]
+	fn unknown_keys_are_refused() {
+		assert!(toml::from_str::<Config>("prot = 21").is_err());
+	}
+
+	#[test]
+	fn command_line_takes_precedence_over_the_file() {
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join("ftp-server.toml");
+		fs::write(&path, "port = 2121\nlog_level = \"warn\"\nusers_file = \"file.toml\"\nbanner = \"Hello\"\n").unwrap();
+		let file = path.to_str().unwrap();
+
+		let config = Config::load(Args::parse_from(["ftp-server", "--config", file])).unwrap();
+		assert_eq!((config.port, config.log_level), (2121, LevelFilter::Warn));
+		assert_eq!(config.users_file, PathBuf::from("file.toml"));
+
+		let args = Args::parse_from(["ftp-server", "-c", file, "-p", "2100", "--log-level", "debug", "-u", "args.toml", "-l", "::1"]);
+		let config = Config::load(args).unwrap();
+		assert_eq!((config.port, config.log_level), (2100, LevelFilter::Debug));
+		assert_eq!(config.users_file, PathBuf::from("args.toml"));
+		assert_eq!(config.listen_addresses, vec![IpAddr::V6(Ipv6Addr::LOCALHOST)]);
+		// What the command line doesn't give comes from the file
+		assert_eq!(config.banner, "Hello");
+
+		// The command line values are checked too
+		fs::write(&path, "port = 2121\nlisten_addresses = []\n").unwrap();
+		assert!(Config::load(Args::parse_from(["ftp-server", "-c", file])).is_err());
+		assert!(Config::load(Args::parse_from(["ftp-server", "-c", file, "-l", "127.0.0.1"])).is_ok());
+	}
+}
@@ -16,21 +16,21 @@ You should have received a copy of the GNU General Public License
 along with rust-discovery.  If not, see <http://www.gnu.org/licenses/>. */
 
 extern crate core;
-use log::{debug, error, info, Level};
+use log::{error, info};
 
 use async_shutdown::Shutdown;
+use clap::Parser;
 
+mod config;
 mod protocol;
 mod server;
+mod storage;
 mod utils;
+use config::{Args, Config};
+use server::ServerContext;
 use server::client::Client;
 use utils::logger;
 
-pub const ADDR: &str = "127.0.0.1";
-pub const PORT: &str = "8080";
-
-pub const LEVEL: Level = Level::Info;
-
 async fn wait_ctrl_c(shutdown: Shutdown) {
 	
 	// Spawn a task to wait for CTRL+C and trigger a shutdown.
@@ -48,15 +48,24 @@ async fn wait_ctrl_c(shutdown: Shutdown) {
 }
 
 
-async fn server() {
+async fn server(config: Config) {
 	// Create a new shutdown object.
 	// We will clone it into all tasks that need it.
 	let shutdown = Shutdown::new();
 	
 	wait_ctrl_c(shutdown.clone()).await;
+
+	// Load the users, the credentials and the TLS certificate.
+	let context = match ServerContext::new(config) {
+		Ok(context) => context,
+		Err(e) => {
+			error!("Failed to start server: {}", e);
+			std::process::exit(1);
+		}
+	};
 	
 	// Run the server and set a non-zero exit code if we had an error.
-	let exit_code = match server::run(shutdown.clone()).await {
+	let exit_code = match server::run(shutdown.clone(), context).await {
 		Ok(()) => 0,
 		Err(e) => {
 			error!("Server task finished with an error: {}", e);
@@ -73,12 +82,18 @@ async fn server() {
 
 #[tokio::main]
 async fn main() {
+
+	let config = match Config::load(Args::parse()) {
+		Ok(config) => config,
+		Err(e) => {
+			eprintln!("Failed to load configuration: {}", e);
+			std::process::exit(1);
+		}
+	};
 	
-	if let Err(e) = logger::init() {
+	if let Err(e) = logger::init(config.log_level) {
 		error!("Failed to init logger: {:?}", e);
 	}
 	
-	server().await;
+	server(config).await;
 }
-
-
@@ -17,9 +17,13 @@ along with rust-discovery.  If not, see <http://www.gnu.org/licenses/>. */
 
 extern crate num;
 use std::fmt::{Display, Formatter};
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
 use std::path::PathBuf;
 use log::debug;
 
+pub mod reply;
+
 use self::ServerResponse::*;
 use self::ClientCommand::*;
 
@@ -40,20 +44,21 @@ pub enum ServerResponse {
 	SystemType = 215,
 	ServiceReadyForNewUser = 220,
 	ServiceClosingControlConnection = 221,
-	RecognizedCommandsBeginMessage = 2240,
-	RecognizedCommandsEndMessage = 2241,
 	DataConnectionOpen = 225,
 	ClosingDataConnection = 226,
 	EnteringPassiveMode = 227,
+	EnteringExtendedPassiveMode = 229,
 	UserLoggedIn = 230,
+	SecurityDataExchangeComplete = 234,
 	RequestedFileActionOkay = 250,
 	PathNameCreated = 257,
 	UserNameOkayNeedPassword = 331,
 	NeedAccountForLogin = 332,
 	RequestedFileActionPendingFurtherInformation = 350,
-	ServiceNotAvailable = 421, // ==> use it for timeout connection !
+	ServiceNotAvailable = 421,
 	CantOpenDataConnection = 425,
 	ConnectionClosed = 426,
+	NeedUnavailableResource = 431,
 	FileBusy = 450,
 	LocalErrorInProcessing = 451,
 	InsufficientStorageSpace = 452,
@@ -62,9 +67,12 @@ pub enum ServerResponse {
 	CommandNotImplemented = 502,
 	BadSequenceOfCommands = 503,
 	CommandNotImplementedForThatParameter = 504,
-	AlreadyExists = 521,
+	NetworkProtocolNotSupported = 522,
+	ProtectionRequired = 521, // RFC 4217: the data connection must be protected with PROT P
 	NotLoggedIn = 530,
 	NeedAccountForStoringFiles = 532,
+	RequestDeniedForPolicyReasons = 534,
+	ProtectionLevelNotSupported = 536,
 	PermissionDenied = 550,
 	PageTypeUnknown = 551,
 	ExceededStorageAllocation = 552,
@@ -85,22 +93,23 @@ impl Display for ServerResponse {
 			FileStatus => { write!(f, "{}", FileStatus as i32) },
 			HelpMessage => { write!(f, "{} Help ", HelpMessage as i32) },
 			SystemType => { write!(f, "{} UNIX Type: L8", SystemType as i32) },
-			ServiceReadyForNewUser => { write!(f, "{} Welcome to my rust ftp server. I'm waiting for your user name ", ServiceReadyForNewUser as i32) },
+			ServiceReadyForNewUser => { write!(f, "{}", ServiceReadyForNewUser as i32) },
 			ServiceClosingControlConnection => { write!(f, "{} Goodbye ", ServiceClosingControlConnection as i32) },
-			RecognizedCommandsBeginMessage => { write!(f, "{}-Recognized commands \n", RecognizedCommandsBeginMessage as i32) },
-			RecognizedCommandsEndMessage => { write!(f, "{} Help Ok ", RecognizedCommandsEndMessage as i32) },
 			DataConnectionOpen => { write!(f, "{} Data connection open ", DataConnectionOpen as i32) },
 			ClosingDataConnection => { write!(f, "{}", ClosingDataConnection as i32) },
 			EnteringPassiveMode => { write!(f, "{} Entering Passive Mode", EnteringPassiveMode as i32) },
+			EnteringExtendedPassiveMode => { write!(f, "{} Entering Extended Passive Mode", EnteringExtendedPassiveMode as i32) },
 			UserLoggedIn => { write!(f, "{} User logged in ", UserLoggedIn as i32) },
+			SecurityDataExchangeComplete => { write!(f, "{}", SecurityDataExchangeComplete as i32) },
 			RequestedFileActionOkay => { write!(f, "{}", RequestedFileActionOkay as i32) },
 			PathNameCreated => { write!(f, "{}", PathNameCreated as i32) },
 			UserNameOkayNeedPassword => { write!(f, "{} Please specify the password ", UserNameOkayNeedPassword as i32) },
 			NeedAccountForLogin => { write!(f, "{} Need account for login ", NeedAccountForLogin as i32) },
 			RequestedFileActionPendingFurtherInformation => { write!(f, "{}", RequestedFileActionPendingFurtherInformation as i32) },
-			ServiceNotAvailable => { write!(f, "{} Timeout ", ServiceNotAvailable as i32) },
+			ServiceNotAvailable => { write!(f, "{}", ServiceNotAvailable as i32) },
 			CantOpenDataConnection => { write!(f, "{} Can't open data connection ", CantOpenDataConnection as i32) },
 			ConnectionClosed => { write!(f, "{} Connection closed", ConnectionClosed as i32) },
+			NeedUnavailableResource => { write!(f, "{}", NeedUnavailableResource as i32) },
 			FileBusy => { write!(f, "{} File busy ", FileBusy as i32) },
 			LocalErrorInProcessing => { write!(f, "{} Local error ", LocalErrorInProcessing as i32) },
 			InsufficientStorageSpace => { write!(f, "{} No space left ", InsufficientStorageSpace as i32) },
@@ -109,13 +118,16 @@ impl Display for ServerResponse {
 			CommandNotImplemented => { write!(f, "{} Not implemented yet ", CommandNotImplemented as i32) },
 			BadSequenceOfCommands => { write!(f, "{} Bad command ", BadSequenceOfCommands as i32) },
 			CommandNotImplementedForThatParameter => { write!(f, "{} Not implemented for thet parameter ", CommandNotImplementedForThatParameter as i32) },
+			NetworkProtocolNotSupported => { write!(f, "{} Network protocol not supported,", NetworkProtocolNotSupported as i32) },
 			NotLoggedIn => { write!(f, "{} Please login with USER and PASS ", NotLoggedIn as i32) },
 			NeedAccountForStoringFiles => { write!(f, "{} need account for storing files ", NeedAccountForStoringFiles as i32) },
+			RequestDeniedForPolicyReasons => { write!(f, "{}", RequestDeniedForPolicyReasons as i32) },
+			ProtectionLevelNotSupported => { write!(f, "{}", ProtectionLevelNotSupported as i32) },
 			PermissionDenied => { write!(f, "{}", PermissionDenied as i32) }
 			PageTypeUnknown => { write!(f, "{} Page type unknown ", PageTypeUnknown as i32) },
 			ExceededStorageAllocation => { write!(f, "{} Exceeded space allocated ", ExceededStorageAllocation as i32) },
 			FileNameNotAllowed => { write!(f, "{} File name not allowed ", FileNameNotAllowed as i32) },
-			AlreadyExists => { write!(f, "{}", AlreadyExists as i32) },
+			ProtectionRequired => { write!(f, "{}", ProtectionRequired as i32) },
 		}
 	}
 }
@@ -155,20 +167,29 @@ impl Display for TransfertMode {
 pub const ABOR: &str = "ABOR";
 pub const ALLO: &str = "ALLO";
 pub const APPE: &str = "APPE";
-pub const _AUTH: &str = "AUTH";
+pub const AUTH: &str = "AUTH";
 pub const ACCT: &str = "ACCT";
 pub const CDUP: &str = "CDUP";
 pub const CWD: &str = "CWD";
 pub const DELE: &str = "DELE";
+pub const EPRT: &str = "EPRT";
+pub const EPSV: &str = "EPSV";
+pub const FEAT: &str = "FEAT";
 pub const HELP: &str = "HELP";
 pub const LIST: &str = "LIST";
+pub const MDTM: &str = "MDTM";
 pub const MKD: &str = "MKD";
+pub const MLSD: &str = "MLSD";
+pub const MLST: &str = "MLST";
 pub const MODE: &str = "MODE";
 pub const NLIST: &str = "NLST";
 pub const NOOP: &str = "NOOP";
+pub const OPTS: &str = "OPTS";
 pub const PASS: &str = "PASS";
 pub const PASV: &str = "PASV";
+pub const PBSZ: &str = "PBSZ";
 pub const PORT: &str = "PORT";
+pub const PROT: &str = "PROT";
 pub const PWD: &str = "PWD";
 pub const QUIT: &str = "QUIT";
 pub const REIN: &str = "REIN";
@@ -178,6 +199,7 @@ pub const RMD: &str = "RMD";
 pub const RNFR: &str = "RNFR";
 pub const RNTO: &str = "RNTO";
 pub const SITE: &str = "SITE";
+pub const SIZE: &str = "SIZE";
 pub const SMNT: &str = "SMNT";
 pub const STAT: &str = "STAT";
 pub const STOR: &str = "STOR";
@@ -192,21 +214,31 @@ pub const UNKN: &str = "UNKN";
 #[allow(dead_code)]
 pub enum ClientCommand {
 	Abor,
-	Allo(u32),
+	Allo(u64),
 	Appe(PathBuf),
 	Acct(String),
+	Auth(String),
 	CdUp,
 	Cwd(PathBuf),
 	Dele(PathBuf),
-	Help(String),
+	Eprt(String),
+	Epsv(Option<String>),
+	Feat,
+	Help(Option<String>),
 	List(Option<PathBuf>),
+	Mdtm(PathBuf),
 	Mkd(PathBuf),
+	Mlsd(Option<PathBuf>),
+	Mlst(Option<PathBuf>),
 	Mode,
 	Nlist(Option<PathBuf>),
 	NoOp,
+	Opts(String),
 	Pass(String),
 	Pasv,
+	Pbsz(String),
 	Port(String),
+	Prot(String),
 	Pwd,
 	Quit,
 	Rein,
@@ -216,8 +248,9 @@ pub enum ClientCommand {
 	Rnto(PathBuf),
 	Rnfr(PathBuf),
 	Site(String),
+	Size(PathBuf),
 	Smnt(PathBuf),
-	Stat(PathBuf),
+	Stat(Option<PathBuf>),
 	Stor(PathBuf),
 	Stou(PathBuf),
 	Stru,
@@ -227,117 +260,195 @@ pub enum ClientCommand {
 	User(String),
 }
 
-impl ClientCommand {
-	pub fn new_with_args(input: &str, arg: &str) -> ClientCommand {
-		debug!("ClientCommant::new {} {}", &input, &arg);
+/**
+ * A command understood by the server: its name, its usage line for HELP and how to build it from its argument.
+ * The parser only knows the commands of this table, so HELP cannot miss a command.
+ */
+pub struct Command {
+	pub name: &'static str,
+	pub usage: &'static str,
+	build: fn(Option<&[u8]>) -> Option<ClientCommand>,
+}
 
-		match input {
-			ALLO => Allo(arg.to_string().parse::<u32>().unwrap()),
-			APPE => Appe(PathBuf::from(arg.to_string())),
-			ACCT => Acct(arg.to_string()),
-			CWD => Cwd(PathBuf::from(arg.to_string())),
-			DELE => Dele(PathBuf::from(arg.to_string())),
-			HELP => Help(arg.to_string()),
-			LIST => List(Some(PathBuf::from(arg.to_string()))),
-			MKD => Mkd(PathBuf::from(arg.to_string())),
-			NLIST => Nlist(Some(PathBuf::from(arg.to_string()))),
-			PASS => Pass(arg.to_string()),
-			PORT => Port(arg.to_string()),
-			REST => Rest(arg.to_string()),
-			RETR => Retr(PathBuf::from(arg.to_string())),
-			RMD => Rmd(PathBuf::from(arg.to_string())),
-			RNFR => Rnfr(PathBuf::from(arg.to_string())),
-			RNTO => Rnto(PathBuf::from(arg.to_string())),
-			SITE => Site(arg.to_string()),
-			SMNT => Smnt(PathBuf::from(arg.to_string())),
-			STAT => Stat(PathBuf::from(arg.to_string())),
-			STOR => Stor(PathBuf::from(arg.to_string())),
-			STOU => Stou(PathBuf::from(arg.to_string())),
-			TYPE => {
-				match arg {
-					"A" => Type(TransferType::Ascii),
-					"I" => Type(TransferType::Binary),
-					_ => Type(TransferType::Unknown),
-				}
-			},
-			USER => User(arg.to_string()),
-			_ => {
-				Unknown(arg.to_string())
-			},
+pub const COMMANDS: &[Command] = &[
+	Command { name: ABOR, usage: "ABOR (abort the current transfer)", build: |arg| without_arg(arg, Abor) },
+	Command { name: ACCT, usage: "ACCT <account>", build: |arg| Some(Acct(text(arg?))) },
+	Command { name: ALLO, usage: "ALLO <size> [R <record size>]", build: |arg| Some(Allo(text(arg?).split(' ').next()?.parse::<u64>().ok()?)) },
+	Command { name: APPE, usage: "APPE <file>", build: |arg| Some(Appe(path(arg?))) },
+	Command { name: AUTH, usage: "AUTH TLS", build: |arg| Some(Auth(text(arg?))) },
+	Command { name: CDUP, usage: "CDUP (go to the parent directory)", build: |arg| without_arg(arg, CdUp) },
+	Command { name: CWD, usage: "CWD <directory>", build: |arg| Some(Cwd(path(arg?))) },
+	Command { name: DELE, usage: "DELE <file>", build: |arg| Some(Dele(path(arg?))) },
+	Command { name: EPRT, usage: "EPRT |<protocol>|<address>|<port>|", build: |arg| Some(Eprt(text(arg?))) },
+	Command { name: EPSV, usage: "EPSV [<protocol> | ALL]", build: |arg| Some(Epsv(arg.map(text))) },
+	Command { name: FEAT, usage: "FEAT (list the supported extensions)", build: |arg| without_arg(arg, Feat) },
+	Command { name: HELP, usage: "HELP [<command>]", build: |arg| Some(Help(arg.map(text))) },
+	Command { name: LIST, usage: "LIST [<path>]", build: |arg| Some(List(arg.map(path))) },
+	Command { name: MDTM, usage: "MDTM <file>", build: |arg| Some(Mdtm(path(arg?))) },
+	Command { name: MKD, usage: "MKD <directory>", build: |arg| Some(Mkd(path(arg?))) },
+	Command { name: MLSD, usage: "MLSD [<directory>]", build: |arg| Some(Mlsd(arg.map(path))) },
+	Command { name: MLST, usage: "MLST [<path>]", build: |arg| Some(Mlst(arg.map(path))) },
+	Command { name: MODE, usage: "MODE <mode>", build: |_| Some(Mode) },
+	Command { name: NLIST, usage: "NLST [<path>]", build: |arg| Some(Nlist(arg.map(path))) },
+	Command { name: NOOP, usage: "NOOP (do nothing)", build: |arg| without_arg(arg, NoOp) },
+	Command { name: OPTS, usage: "OPTS <command> [<options>]", build: |arg| Some(Opts(text(arg?))) },
+	Command { name: PASS, usage: "PASS <password>", build: |arg| Some(Pass(arg.map(text).unwrap_or_default())) },
+	Command { name: PASV, usage: "PASV (enter passive mode)", build: |arg| without_arg(arg, Pasv) },
+	Command { name: PBSZ, usage: "PBSZ 0", build: |arg| Some(Pbsz(text(arg?))) },
+	Command { name: PORT, usage: "PORT <h1,h2,h3,h4,p1,p2>", build: |arg| Some(Port(text(arg?))) },
+	Command { name: PROT, usage: "PROT <C | P>", build: |arg| Some(Prot(text(arg?))) },
+	Command { name: PWD, usage: "PWD (print the current directory)", build: |arg| without_arg(arg, Pwd) },
+	Command { name: QUIT, usage: "QUIT (close the connection)", build: |arg| without_arg(arg, Quit) },
+	Command { name: REIN, usage: "REIN (reinitialize the session)", build: |arg| without_arg(arg, Rein) },
+	Command { name: REST, usage: "REST <offset>", build: |arg| Some(Rest(text(arg?))) },
+	Command { name: RETR, usage: "RETR <file>", build: |arg| Some(Retr(path(arg?))) },
+	Command { name: RMD, usage: "RMD <directory>", build: |arg| Some(Rmd(path(arg?))) },
+	Command { name: RNFR, usage: "RNFR <path>", build: |arg| Some(Rnfr(path(arg?))) },
+	Command { name: RNTO, usage: "RNTO <path>", build: |arg| Some(Rnto(path(arg?))) },
+	Command { name: SITE, usage: "SITE <command>", build: |arg| Some(Site(text(arg?))) },
+	Command { name: SIZE, usage: "SIZE <file>", build: |arg| Some(Size(path(arg?))) },
+	Command { name: SMNT, usage: "SMNT <path>", build: |arg| Some(Smnt(path(arg?))) },
+	Command { name: STAT, usage: "STAT [<path>]", build: |arg| Some(Stat(arg.map(path))) },
+	Command { name: STOR, usage: "STOR <file>", build: |arg| Some(Stor(path(arg?))) },
+	Command { name: STOU, usage: "STOU <file>", build: |arg| Some(Stou(path(arg?))) },
+	Command { name: STRU, usage: "STRU <structure>", build: |_| Some(Stru) },
+	Command { name: SYST, usage: "SYST (print the system type)", build: |arg| without_arg(arg, Syst) },
+	Command { name: TYPE, usage: "TYPE <A | I>", build: |arg| {
+		match text(arg?).as_str() {
+			"A" => Some(Type(TransferType::Ascii)),
+			"I" => Some(Type(TransferType::Binary)),
+			_ => Some(Type(TransferType::Unknown)),
 		}
+	} },
+	Command { name: USER, usage: "USER <username>", build: |arg| Some(User(text(arg?))) },
+];
+
+/**
+ * Path argument, kept as raw bytes: file names are not always valid UTF-8
+ */
+fn path(arg: &[u8]) -> PathBuf {
+	PathBuf::from(OsStr::from_bytes(arg))
+}
+
+fn text(arg: &[u8]) -> String {
+	String::from_utf8_lossy(arg).into_owned()
+}
+
+fn without_arg(arg: Option<&[u8]>, command: ClientCommand) -> Option<ClientCommand> {
+	match arg {
+		None => Some(command),
+		Some(_) => None,
 	}
+}
 
-	pub fn new_without_arg(input: &str) -> ClientCommand {
-		debug!("ClientCommant::new {}", &input);
+impl Command {
+	pub fn find(name: &str) -> Option<&'static Command> {
+		COMMANDS.iter().find(|command| command.name.eq_ignore_ascii_case(name))
+	}
+}
 
-		match input {
-			ABOR => Abor,
-			CDUP => CdUp,
-			MODE => Mode,
-			NOOP => NoOp,
-			PWD => Pwd,
-			PASV => Pasv,
-			QUIT => Quit,
-			REIN => Rein,
-			STRU => Stru,
-			SYST => Syst,
-			LIST => List(None),
-			NLIST => Nlist(None),
-			_ => {
-				Unknown("Unknown".to_string())
-			},
+impl ClientCommand {
+	pub fn new(input: &str, arg: Option<&[u8]>) -> ClientCommand {
+		debug!("ClientCommant::new {} {}", &input, String::from_utf8_lossy(arg.unwrap_or_default()));
+
+		if let Some(command) = Command::find(input) {
+			if let Some(command) = (command.build)(arg) {
+				return command;
+			}
 		}
+		Unknown(input.to_string())
 	}
 }
 
 impl Display for ClientCommand {
 	fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
 		match self {
-			Cwd(arg) => write!(f, "{} {}", CWD, arg.as_path().to_str().unwrap()),
+			Cwd(arg) => write!(f, "{} {}", CWD, arg.as_path().display()),
 			List(arg) => {
 				if let Some(path) = arg {
-					write!(f, "{} {}", LIST, path.as_path().to_str().unwrap())
+					write!(f, "{} {}", LIST, path.as_path().display())
 				} else {
 					write!(f, "{}", LIST)
 				}
 			},
 			Pass(_arg) => write!(f, "{} xxxx", PASS),
+			Pbsz(arg) => write!(f, "{} {}", PBSZ, arg),
 			Port(arg) => write!(f, "{} {}", PORT, arg),
+			Prot(arg) => write!(f, "{} {}", PROT, arg),
 			Pwd => write!(f, "{}", PWD),
 			Pasv => write!(f, "{}", PASV),
 			Quit => write!(f, "{}", QUIT),
-			Retr(arg) => write!(f, "{} {}", RETR, arg.as_path().to_str().unwrap()),
-			Stor(arg) => write!(f, "{} {}", STOR, arg.as_path().to_str().unwrap()),
+			Retr(arg) => write!(f, "{} {}", RETR, arg.as_path().display()),
+			Stor(arg) => write!(f, "{} {}", STOR, arg.as_path().display()),
 			Syst => write!(f, "{}", SYST),
 			Type(arg) => write!(f, "{} {}", TYPE, arg),
 			User(arg) => write!(f, "{} {}", USER, arg),
 			CdUp => write!(f, "{}", CDUP),
-			Mkd(arg) => write!(f, "{} {}", MKD, arg.as_path().to_str().unwrap()),
-			Rmd(arg) => write!(f, "{} {}", RMD, arg.as_path().to_str().unwrap()),
+			Mdtm(arg) => write!(f, "{} {}", MDTM, arg.as_path().display()),
+			Mkd(arg) => write!(f, "{} {}", MKD, arg.as_path().display()),
+			Rmd(arg) => write!(f, "{} {}", RMD, arg.as_path().display()),
 			NoOp => write!(f, "{}", NOOP),
+			Opts(arg) => write!(f, "{} {}", OPTS, arg),
 			Unknown(arg) => write!(f, "{} {}", UNKN, arg), // doesn't exist in the protocol
 			Abor => write!(f, "{}", ABOR),
 			Allo(arg) => write!(f, "{} {}", ALLO, arg),
-			Appe(arg) => write!(f, "{} {}", APPE, arg.as_path().to_str().unwrap()),
+			Appe(arg) => write!(f, "{} {}", APPE, arg.as_path().display()),
 			Acct(arg) => write!(f, "{} {}", ACCT, arg),
-			Dele(arg) => write!(f, "{} {}", DELE, arg.as_path().to_str().unwrap()),
-			Help(arg) => write!(f, "{} {}", HELP, arg),
+			Auth(arg) => write!(f, "{} {}", AUTH, arg),
+			Dele(arg) => write!(f, "{} {}", DELE, arg.as_path().display()),
+			Eprt(arg) => write!(f, "{} {}", EPRT, arg),
+			Epsv(arg) => {
+				if let Some(arg) = arg {
+					write!(f, "{} {}", EPSV, arg)
+				} else {
+					write!(f, "{}", EPSV)
+				}
+			},
+			Feat => write!(f, "{}", FEAT),
+			Help(arg) => {
+				if let Some(arg) = arg {
+					write!(f, "{} {}", HELP, arg)
+				} else {
+					write!(f, "{}", HELP)
+				}
+			},
+			Mlsd(arg) => {
+				if let Some(path) = arg {
+					write!(f, "{} {}", MLSD, path.as_path().display())
+				} else {
+					write!(f, "{}", MLSD)
+				}
+			},
+			Mlst(arg) => {
+				if let Some(path) = arg {
+					write!(f, "{} {}", MLST, path.as_path().display())
+				} else {
+					write!(f, "{}", MLST)
+				}
+			},
 			Mode => write!(f, "{}", MODE),
 			Nlist(arg) => {
 				if let Some(path) = arg {
-					write!(f, "{} {}", NLIST, path.as_path().to_str().unwrap())
+					write!(f, "{} {}", NLIST, path.as_path().display())
 				} else {
 					write!(f, "{}", NLIST)
 				}
 			},
 			Rein => write!(f, "{}", REIN),
 			Rest(arg) => write!(f, "{} {}", REST, arg),
-			Rnto(arg) => write!(f, "{} {}", RNTO, arg.as_path().to_str().unwrap()),
-			Rnfr(arg) => write!(f, "{} {}", RNFR, arg.as_path().to_str().unwrap()),
+			Rnto(arg) => write!(f, "{} {}", RNTO, arg.as_path().display()),
+			Rnfr(arg) => write!(f, "{} {}", RNFR, arg.as_path().display()),
 			Site(arg) => write!(f, "{} {}", SITE, arg),
-			Smnt(arg) => write!(f, "{} {}", SMNT, arg.as_path().to_str().unwrap()),
-			Stat(arg) => write!(f, "{} {}", STAT, arg.as_path().to_str().unwrap()),
-			Stou(arg) => write!(f, "{} {}", STOU, arg.as_path().to_str().unwrap()),
+			Size(arg) => write!(f, "{} {}", SIZE, arg.as_path().display()),
+			Smnt(arg) => write!(f, "{} {}", SMNT, arg.as_path().display()),
+			Stat(arg) => {
+				if let Some(path) = arg {
+					write!(f, "{} {}", STAT, path.as_path().display())
+				} else {
+					write!(f, "{}", STAT)
+				}
+			},
+			Stou(arg) => write!(f, "{} {}", STOU, arg.as_path().display()),
 			Stru => write!(f, "{}", STRU),
 		}
 	}
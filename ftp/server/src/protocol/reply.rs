@@ -0,0 +1,95 @@
+/* Copyright 2022 Pierrick MARIE
+
+This file is part of rust-discovery
+
+LCS is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+Rust-discovery is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with rust-discovery.  If not, see <http://www.gnu.org/licenses/>. */
+
+use crate::protocol::ServerResponse;
+
+/**
+ * Builder of a reply, see RFC 959 section 4.2.
+ * A reply of several lines starts with "NNN-", ends with "NNN " and its other lines start with a space,
+ * so no line in the middle can be mistaken for the end of the reply.
+ */
+pub struct Reply {
+	response: ServerResponse,
+	lines: Vec<Vec<u8>>,
+}
+
+impl Reply {
+	pub fn new(response: ServerResponse) -> Self {
+		Reply {
+			response,
+			lines: vec![],
+		}
+	}
+
+	/**
+	 * Add a line to the reply. The line is given as bytes since it may contain a file name.
+	 */
+	pub fn line<T: AsRef<[u8]>>(mut self, line: T) -> Self {
+		self.lines.push(line.as_ref().to_vec());
+		self
+	}
+
+	/**
+	 * The reply as it is sent on the control connection, without the last end of line
+	 */
+	pub fn to_bytes(&self) -> Vec<u8> {
+		let code = (self.response as u32).to_string();
+		let mut reply = code.clone().into_bytes();
+
+		for (i, line) in self.lines.iter().enumerate() {
+			if i > 0 {
+				reply.extend_from_slice(b"\r\n");
+			}
+			if i == self.lines.len() - 1 {
+				if i > 0 {
+					reply.extend_from_slice(code.as_bytes());
+				}
+				reply.push(b' ');
+			} else if i == 0 {
+				reply.push(b'-');
+			} else {
+				reply.push(b' ');
+			}
+			reply.extend_from_slice(line.as_slice());
+		}
+		reply
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn single_line() {
+		let reply = Reply::new(ServerResponse::OK).line("Command okay");
+		assert_eq!(reply.to_bytes(), b"200 Command okay");
+	}
+
+	#[test]
+	fn several_lines() {
+		let reply = Reply::new(ServerResponse::SystemStatus).line("Extensions supported:").line("MDTM").line("End");
+		assert_eq!(reply.to_bytes(), b"211-Extensions supported:\r\n MDTM\r\n211 End");
+	}
+
+	#[test]
+	fn middle_lines_cannot_end_the_reply() {
+		// A file name which looks like the last line of a reply, and is not valid UTF-8
+		let reply = Reply::new(ServerResponse::FileStatus).line("Status follows").line(b"211 end\xff.txt").line("End of status");
+		assert_eq!(reply.to_bytes(), b"213-Status follows\r\n 211 end\xff.txt\r\n213 End of status");
+	}
+}
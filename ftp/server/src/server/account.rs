@@ -0,0 +1,171 @@
+/* Copyright 2022 Pierrick MARIE
+
+This file is part of rust-discovery
+
+LCS is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+Rust-discovery is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with rust-discovery.  If not, see <http://www.gnu.org/licenses/>. */
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+
+use log::{debug, error, info};
+use serde::Deserialize;
+
+use crate::server::auth::{is_supported_hash, verify_hash, verify_unknown_user, Authenticator};
+
+/**
+ * A virtual user declared in the users file. It doesn't need any Unix account.
+ *
+ * ```toml
+ * [[user]]
+ * name = "partner"
+ * password = "$argon2id$v=19$m=19456,t=2,p=1$..."
+ * root = "/srv/ftp/partner"
+ * read_only = true
+ * quota_bytes = 1073741824
+ * quota_files = 1000
+ * max_logins = 2
+ * admin = false
+ * ```
+ */
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Account {
+	pub name: String,
+	pub password: String, // argon2 or bcrypt hash
+	pub root: PathBuf,
+	#[serde(default)]
+	pub read_only: bool,
+	pub quota_bytes: Option<u64>, // Most bytes the user can store, unlimited if missing
+	pub quota_files: Option<u64>, // Most files the user can store, unlimited if missing
+	pub max_logins: Option<usize>, // Overrides max_logins_per_user of the configuration
+	#[serde(default)]
+	pub admin: bool, // Allowed to use SITE BANS and SITE UNBAN
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct UsersFile {
+	#[serde(default, rename = "user")]
+	users: Vec<Account>,
+}
+
+/**
+ * All the virtual users known by the server, indexed by name.
+ */
+pub struct Accounts {
+	accounts: HashMap<String, Account>,
+}
+
+impl Accounts {
+	pub fn load(path: &Path) -> std::io::Result<Self> {
+		debug!("Accounts::load {:?}", path);
+		let accounts = Accounts::parse(fs::read_to_string(path)?.as_str(), path)?;
+		info!("{} users loaded from {:?}", accounts.accounts.len(), path);
+		Ok(accounts)
+	}
+
+	/**
+	 * Read the content of the users file, path is only used in the error messages
+	 */
+	fn parse(content: &str, path: &Path) -> std::io::Result<Self> {
+		let users_file: UsersFile = toml::from_str(content)
+			.map_err(|e| Error::new(ErrorKind::InvalidData, format!("{:?}: {}", path, e)))?;
+
+		let mut accounts = HashMap::new();
+		for account in users_file.users {
+			if !account.root.is_dir() {
+				error!("Root directory of user {} is not a directory: {:?}", account.name, account.root);
+			}
+			if !is_supported_hash(account.password.as_str()) {
+				let msg = format!("{:?}: the password of user {} is not an argon2 or bcrypt hash", path, account.name);
+				return Err(Error::new(ErrorKind::InvalidData, msg));
+			}
+			if accounts.contains_key(&account.name) {
+				let msg = format!("{:?}: user {} declared twice", path, account.name);
+				return Err(Error::new(ErrorKind::InvalidData, msg));
+			}
+			accounts.insert(account.name.clone(), account);
+		}
+		Ok(Accounts { accounts })
+	}
+
+	pub fn get(&self, name: &str) -> Option<Account> {
+		self.accounts.get(name).cloned()
+	}
+}
+
+impl Authenticator for Accounts {
+	fn authenticate(&self, username: &str, password: &str) -> bool {
+		match self.accounts.get(username) {
+			Some(account) => verify_hash(account.password.as_str(), password),
+			None => verify_unknown_user(self.accounts.values().next().map(|account| account.password.as_str()), password),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn users(content: &str) -> std::io::Result<Accounts> {
+		Accounts::parse(content, Path::new("users.toml"))
+	}
+
+	fn user(name: &str, hash: &str) -> String {
+		format!("[[user]]\nname = \"{}\"\npassword = \"{}\"\nroot = \"/tmp\"\n", name, hash)
+	}
+
+	#[test]
+	fn valid_file() {
+		let hash = bcrypt::hash("secret", 4).unwrap();
+		let content = format!("{}{}read_only = true\nquota_bytes = 1000\nmax_logins = 2\nadmin = true\n", user("alice", &hash), user("bob", &hash));
+		let accounts = users(content.as_str()).unwrap();
+		let alice = accounts.get("alice").unwrap();
+		assert_eq!(alice.root, PathBuf::from("/tmp"));
+		assert!(!alice.read_only && !alice.admin);
+		assert_eq!((alice.quota_bytes, alice.quota_files, alice.max_logins), (None, None, None));
+		let bob = accounts.get("bob").unwrap();
+		assert!(bob.read_only && bob.admin);
+		assert_eq!((bob.quota_bytes, bob.max_logins), (Some(1000), Some(2)));
+		assert!(accounts.get("carol").is_none());
+
+		assert!(accounts.authenticate("alice", "secret"));
+		assert!(!accounts.authenticate("alice", "wrong"));
+		assert!(!accounts.authenticate("carol", "secret"));
+		assert!(users("").unwrap().get("alice").is_none());
+	}
+
+	#[test]
+	fn duplicate_user() {
+		let content = format!("{}{}", user("alice", "$2b$04$a"), user("alice", "$2b$04$b"));
+		let error = users(content.as_str()).err().unwrap();
+		assert!(error.to_string().contains("user alice declared twice"), "{}", error);
+	}
+
+	#[test]
+	fn missing_and_unknown_fields() {
+		let error = users("[[user]]\nname = \"alice\"\npassword = \"$2b$04$a\"\n").err().unwrap();
+		assert!(error.to_string().contains("missing field `root`"), "{}", error);
+		let content = format!("{}home = \"/home/alice\"\n", user("alice", "$2b$04$a"));
+		assert_eq!(users(content.as_str()).err().unwrap().kind(), ErrorKind::InvalidData);
+	}
+
+	#[test]
+	fn clear_text_password() {
+		let error = users(user("alice", "secret").as_str()).err().unwrap();
+		assert!(error.to_string().contains("password of user alice is not an argon2 or bcrypt hash"), "{}", error);
+	}
+}
@@ -0,0 +1,189 @@
+/* Copyright 2022 Pierrick MARIE
+
+This file is part of rust-discovery
+
+LCS is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+Rust-discovery is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with rust-discovery.  If not, see <http://www.gnu.org/licenses/>. */
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::Path;
+
+use argon2::{Argon2, PasswordHash, PasswordVerifier};
+use log::{debug, error, info};
+
+/**
+ * Checks the credentials sent by a client with USER and PASS.
+ */
+pub trait Authenticator: Send + Sync {
+	fn authenticate(&self, username: &str, password: &str) -> bool;
+}
+
+/**
+ * Credentials read from a text file, one `username:hash` entry per line.
+ * Hashes are PHC strings produced by argon2 (`$argon2id$...`) or bcrypt (`$2b$...`).
+ * Empty lines and lines starting with '#' are ignored.
+ */
+pub struct PasswordFile {
+	credentials: HashMap<String, String>,
+}
+
+impl PasswordFile {
+	pub fn load(path: &Path) -> std::io::Result<Self> {
+		debug!("PasswordFile::load {:?}", path);
+		let file = PasswordFile::parse(fs::read_to_string(path)?.as_str(), path)?;
+		info!("{} credentials loaded from {:?}", file.credentials.len(), path);
+		Ok(file)
+	}
+
+	/**
+	 * Read the content of the file, path is only used in the error messages
+	 */
+	fn parse(content: &str, path: &Path) -> std::io::Result<Self> {
+		let mut credentials = HashMap::new();
+
+		for (number, line) in content.lines().enumerate() {
+			let line = line.trim();
+			if line.is_empty() || line.starts_with('#') {
+				continue;
+			}
+			match line.split_once(':') {
+				Some((username, hash)) if !username.is_empty() && is_supported_hash(hash) => {
+					credentials.insert(username.to_string(), hash.to_string());
+				}
+				Some((username, _)) if !username.is_empty() => {
+					let msg = format!("{:?} line {}: the hash of {} is not an argon2 or bcrypt hash", path, number + 1, username);
+					return Err(Error::new(ErrorKind::InvalidData, msg));
+				}
+				_ => {
+					let msg = format!("{:?} line {}: expected 'username:hash'", path, number + 1);
+					return Err(Error::new(ErrorKind::InvalidData, msg));
+				}
+			}
+		}
+		Ok(PasswordFile { credentials })
+	}
+}
+
+impl Authenticator for PasswordFile {
+	fn authenticate(&self, username: &str, password: &str) -> bool {
+		match self.credentials.get(username) {
+			Some(hash) => verify_hash(hash, password),
+			None => verify_unknown_user(self.credentials.values().next().map(String::as_str), password),
+		}
+	}
+}
+
+/**
+ * Refuse a user name which has no hash, after checking the password against the hash of another user:
+ * PASS takes as long as for an existing user, so its time does not tell which user names exist.
+ */
+pub fn verify_unknown_user(other_hash: Option<&str>, password: &str) -> bool {
+	if let Some(hash) = other_hash {
+		verify_hash(hash, password);
+	}
+	false
+}
+
+/**
+ * True if hash looks like a hash verify_hash can check, and not like a clear text password
+ */
+pub fn is_supported_hash(hash: &str) -> bool {
+	hash.starts_with("$argon2") || hash.starts_with("$2")
+}
+
+/**
+ * Check a password against an argon2 or bcrypt hash.
+ */
+pub fn verify_hash(hash: &str, password: &str) -> bool {
+	if hash.starts_with("$argon2") {
+		match PasswordHash::new(hash) {
+			Ok(parsed) => Argon2::default().verify_password(password.as_bytes(), &parsed).is_ok(),
+			Err(e) => {
+				error!("Invalid argon2 hash: {}", e);
+				false
+			}
+		}
+	} else if hash.starts_with("$2") {
+		match bcrypt::verify(password, hash) {
+			Ok(valid) => valid,
+			Err(e) => {
+				error!("Invalid bcrypt hash: {}", e);
+				false
+			}
+		}
+	} else {
+		error!("Unsupported password hash format");
+		false
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use argon2::password_hash::{PasswordHasher, SaltString};
+
+	fn password_file() -> PasswordFile {
+		let salt = SaltString::from_b64("c2FsdHNhbHRzYWx0").unwrap();
+		let argon2 = Argon2::default().hash_password(b"argon secret", &salt).unwrap().to_string();
+		let bcrypt = bcrypt::hash("bcrypt secret", 4).unwrap();
+		let content = format!("# users\n\nalice:{}\n  bob:{}  \n", argon2, bcrypt);
+		PasswordFile::parse(content.as_str(), Path::new("passwd")).unwrap()
+	}
+
+	#[test]
+	fn argon2_hash() {
+		let file = password_file();
+		assert!(file.authenticate("alice", "argon secret"));
+		assert!(!file.authenticate("alice", "bcrypt secret"));
+		assert!(!file.authenticate("alice", ""));
+	}
+
+	#[test]
+	fn bcrypt_hash() {
+		let file = password_file();
+		assert!(file.authenticate("bob", "bcrypt secret"));
+		assert!(!file.authenticate("bob", "argon secret"));
+	}
+
+	#[test]
+	fn unknown_user_is_refused() {
+		assert!(!password_file().authenticate("carol", "argon secret"));
+		assert!(!verify_unknown_user(None, "secret"));
+	}
+
+	#[test]
+	fn bad_lines_are_rejected() {
+		for content in ["alice", ":$2b$04$abc", "alice:", "alice:secret"] {
+			let error = PasswordFile::parse(content, Path::new("passwd")).err().unwrap();
+			assert_eq!(error.kind(), ErrorKind::InvalidData, "{}", content);
+		}
+	}
+
+	#[test]
+	fn load_a_file() {
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join("passwd");
+		fs::write(&path, format!("alice:{}\n", bcrypt::hash("secret", 4).unwrap())).unwrap();
+		assert!(PasswordFile::load(&path).unwrap().authenticate("alice", "secret"));
+		assert_eq!(PasswordFile::load(&dir.path().join("missing")).err().unwrap().kind(), ErrorKind::NotFound);
+	}
+
+	#[test]
+	fn invalid_hashes_are_refused() {
+		assert!(!verify_hash("$argon2id$not a hash", "secret"));
+		assert!(!verify_hash("$2b$04$short", "secret"));
+		assert!(!verify_hash("secret", "secret"));
+	}
+}
@@ -15,288 +15,524 @@ GNU General Public License for more details.
 You should have received a copy of the GNU General Public License
 along with rust-discovery.  If not, see <http://www.gnu.org/licenses/>. */
 
-use std::fs;
-use std::fs::{File, OpenOptions};
-use std::io::{Error, ErrorKind, Write};
-use std::net::SocketAddr;
-use std::path::PathBuf;
+use std::collections::VecDeque;
+use std::future::Future;
+use std::io::{Error, ErrorKind};
+use std::net::{IpAddr, SocketAddr};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use crate::protocol::*;
-use regex::Regex;
+use crate::protocol::reply::Reply;
+use chrono::{DateTime, Utc};
+use regex::bytes::Regex;
 
 use log::{debug, error, info};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
-use crate::{ADDR, utils};
+use crate::utils;
 use crate::utils::connection::Connection;
 use crate::utils::error::{FtpError, FtpResult};
 use portpicker::pick_unused_port;
 
-use users::{get_user_by_name, User};
-use users::os::unix::UserExt;
 use crate::protocol::TransfertMode::*;
-use crate::utils::connection;
+use crate::server::account::Account;
+use crate::server::{LoginSlot, ServerContext};
+use crate::server::quota::{Quota, Reservation};
+use crate::server::throttle::{Direction, Throttle};
+use crate::server::session::SessionState;
+use crate::server::transfer::Progress;
+use crate::config::PartialUploads;
+use crate::config::access;
+use crate::utils::ascii::{self, AsciiDecoder};
+use crate::storage::{Metadata, ReadHandle, Storage, WriteHandle};
+use crate::utils::jail::Jail;
+
+const TRANSFER_BUFFER_SIZE: usize = 64 * 1024;
+const ZERO_COPY_CHUNK_SIZE: usize = 1024 * 1024;
+const MAX_DEFERRED_COMMANDS: usize = 16;
 
 pub struct Client {
 	ctrl_connection: Connection,
+	deferred_commands: VecDeque<Vec<u8>>, // Received during a transfer, run after it
 	data_connection: Option<Connection>,
 	transfert_mode: TransfertMode,
 	transfert_type: TransferType,
-	user: Option<User>,
-	current_work_directory: Option<PathBuf>,
-	current_working_path: Option<PathBuf>,
+	user: Option<Account>,
+	login: Option<LoginSlot>, // Counts the session in the logins of the user
+	storage: Option<Arc<dyn Storage>>, // Files of the logged user
+	quota: Option<Arc<Quota>>, // None if the logged user has no quota
+	reservation: Option<Reservation>, // Booked by ALLO for the next upload
+	download_throttle: Throttle, // Bandwidth limits of the logged user
+	upload_throttle: Throttle,
+	current_work_directory: Option<PathBuf>, // virtual path, "/" is the root directory of the user
+	state: SessionState,
+	pbsz_done: bool,
+	protected_data: bool, // PROT P
+	epsv_all: bool, // After EPSV ALL, only EPSV can be used to open data connections
+	context: Arc<ServerContext>,
 	id: i32,
 }
 
 impl Client {
-	pub fn new(connection: Connection, id: i32) -> Self {
+	pub fn new(connection: Connection, id: i32, context: Arc<ServerContext>) -> Self {
 		Client {
 			ctrl_connection: connection,
+			deferred_commands: VecDeque::new(),
 			data_connection: None,
 			transfert_mode: Active,
 			transfert_type: TransferType::Ascii,
 			user: None,
+			login: None,
+			storage: None,
+			quota: None,
+			reservation: None,
+			download_throttle: Throttle::default(),
+			upload_throttle: Throttle::default(),
 			current_work_directory: None,
-			current_working_path: None,
+			state: SessionState::AwaitingUser,
+			pbsz_done: false,
+			protected_data: false,
+			epsv_all: false,
+			context,
 			id,
 		}
 	}
 
 	pub async fn run(&mut self) -> std::io::Result<()> {
-		if let Err(e) = self.ctrl_connection.sendResponse(ServerResponse::ServiceReadyForNewUser, "Waiting for new user").await {
+		let banner = self.context.config.banner.clone();
+		if let Err(e) = self.ctrl_connection.sendResponse(ServerResponse::ServiceReadyForNewUser, banner.as_str()).await {
 			return Err(Error::new(ErrorKind::NotConnected, e.to_string()));
 		}
 
-		if self.connect().await {
-			info!("Connected {}", self.user.as_ref().unwrap().name().to_str().unwrap());
-			if let Err(e) = self.command().await {
-				error!("{}", e);
-			}
-		} else {
-			error!("Not connected");
+		if let Err(e) = self.command().await {
+			error!("{}", e);
 		}
 
 		self.close_connection().await;
 		Ok(())
 	}
 
-	async fn connect(&mut self) -> bool {
-		match self.user().await {
-			Some(login) => {
-				info!("Login: {}", login);
-				if let Err(e) = self.ctrl_connection.sendResponse(ServerResponse::UserNameOkayNeedPassword, "Waiting for password").await {
-					error!("Not connected {:?}", e);
-				}
-				if self.password().await.is_some() {
-					info!("Password: \"x\"");
-					let user = get_user_by_name(login.trim());
-					if user.is_some() {
-						self.user = user.clone();
-						self.current_work_directory = Some(user.unwrap().home_dir().to_path_buf());
-						if let Err(e) = self.ctrl_connection.sendResponse(ServerResponse::UserLoggedIn, "Logged").await {
-							error!("Not connected {:?}", e);
-						}
-						return true;
-					}
-				}
+	/**
+	 * Commands accepted before the login
+	 */
+	async fn login_command(&mut self, command: ClientCommand) -> FtpResult<()> {
+		match command {
+			ClientCommand::Auth(arg) => self.auth(arg).await,
+			ClientCommand::Feat => self.feat().await,
+			ClientCommand::Help(arg) => self.help(arg).await,
+			ClientCommand::NoOp => self.noop().await,
+			ClientCommand::Opts(arg) => self.opts(arg).await,
+			ClientCommand::Pass(arg) => self.pass(arg).await,
+			ClientCommand::Pbsz(arg) => self.pbsz(arg).await,
+			ClientCommand::Prot(arg) => self.prot(arg).await,
+			ClientCommand::Rein => self.rein().await,
+			ClientCommand::User(arg) => self.user(arg).await,
+			command => {
+				error!("Unexpected command before login: {}", command);
+				let (response, message) = self.state.not_logged_in_reply();
+				self.ctrl_connection.sendResponse(response, message).await
 			}
-			_ => {}
 		}
-		if let Err(e) = self.ctrl_connection.sendResponse(ServerResponse::NotLoggedIn, "Not logged in").await {
-			error!("Not connected {:?}", e);
-		}
-		false
 	}
 
-	async fn user(&mut self) -> Option<String> {
+	async fn user(&mut self, arg: String) -> FtpResult<()> {
 		debug!("client::user");
-		let msg = self.ctrl_connection.read().await?;
+		if self.context.config.require_tls && !self.ctrl_connection.is_secure() {
+			error!("User {} tried to login without TLS", arg);
+			return self.ctrl_connection.sendResponse(ServerResponse::RequestDeniedForPolicyReasons, "TLS required, use AUTH TLS first").await;
+		}
+		if !self.check_word(&arg) {
+			error!("User name error: {}", arg);
+			self.state = SessionState::AwaitingUser;
+			return self.ctrl_connection.sendResponse(ServerResponse::InvalidParameterOrArgument, "Invalid user name").await;
+		}
+		info!("Login: {}", arg);
+		self.state = SessionState::AwaitingPassword(arg);
+		self.ctrl_connection.sendResponse(ServerResponse::UserNameOkayNeedPassword, "Waiting for password").await
+	}
 
-		return match self.parse_command(&msg) {
-			ClientCommand::User(args) => {
-				if self.check_word(&args) {
-					Some(args.clone())
-				} else {
-					error!("User name error: {}", args);
-					None
-				}
-			}
-			err => {
-				error!("Unexpected command: {}", err);
-				None
+	/**
+	 * Check the password of the user given by USER. After a failure, the client can try again with USER.
+	 */
+	async fn pass(&mut self, password: String) -> FtpResult<()> {
+		debug!("client::pass");
+		let login = match self.state.take_login() {
+			Some(login) => login,
+			None => {
+				return self.ctrl_connection.sendResponse(ServerResponse::BadSequenceOfCommands, "Login with USER first").await;
 			}
 		};
+
+		let ip = self.ctrl_connection.peer_addr()?.ip().to_canonical();
+		// A session opened before the ban of its address cannot login either
+		if self.context.login_guard.banned(ip).is_some() {
+			self.state = SessionState::Closing;
+			return self.ctrl_connection.sendResponse(ServerResponse::ServiceNotAvailable, "Too many failed logins, try again later").await;
+		}
+
+		// The password is checked even for an unknown user, see verify_unknown_user
+		let valid = self.check_password(login.as_str(), password).await;
+		if let Some(account) = self.context.accounts.get(login.as_str()).filter(|_| valid) {
+			self.context.login_guard.success(ip, login.as_str());
+			let slot = match self.context.login(&account) {
+				Some(slot) => slot,
+				None => {
+					// The session ends, which gives its place back to the other clients
+					info!("Too many sessions for user {}, connection closed", login);
+					self.state = SessionState::Closing;
+					return self.ctrl_connection.sendResponse(ServerResponse::ServiceNotAvailable, "Too many connections").await;
+				}
+			};
+			// Opening a local storage checks its root directory on disk, the first count of a quota reads it
+			let context = self.context.clone();
+			let root = account.clone();
+			let opened = tokio::task::spawn_blocking(move || {
+				let storage = context.storage(&root)?;
+				let quota = context.quota(&root, storage.as_ref());
+				Ok::<_, Error>((storage, quota))
+			});
+			match opened.await.unwrap_or_else(|e| Err(e.into())) {
+				Ok((storage, quota)) => {
+					self.storage = Some(storage);
+					self.quota = quota;
+					(self.download_throttle, self.upload_throttle) = self.context.throttles(&account);
+					self.current_work_directory = Some(PathBuf::from("/"));
+					self.user = Some(account);
+					self.login = Some(slot);
+					self.state = SessionState::LoggedIn;
+					info!("Connected {}", login);
+					return self.ctrl_connection.sendResponse(ServerResponse::UserLoggedIn, "Logged").await;
+				}
+				Err(e) => {
+					error!("Cannot use root directory {:?} of user {}: {}", account.root, account.name, e);
+				}
+			}
+			return self.ctrl_connection.sendResponse(ServerResponse::NotLoggedIn, "Login incorrect").await;
+		}
+
+		let failure = self.context.login_guard.failure(ip, login.as_str());
+		tokio::time::sleep(failure.delay).await;
+		if failure.banned {
+			self.state = SessionState::Closing;
+			return self.ctrl_connection.sendResponse(ServerResponse::ServiceNotAvailable, "Too many failed logins, try again later").await;
+		}
+		self.ctrl_connection.sendResponse(ServerResponse::NotLoggedIn, "Login incorrect").await
 	}
 
-	async fn password(&mut self) -> Option<String> {
-		debug!("client::password");
-		let msg = self.ctrl_connection.read().await?;
-		return match self.parse_command(&msg) {
-			ClientCommand::Pass(args) => {
-				if self.check_word(&args) {
-					info!("PASSWORD xxx");
-					Some(args.clone())
-				} else {
-					error!("Password error: {}", args);
-					None
+	/**
+	 * Ask the authenticator to check the password. Hash verification is CPU intensive, so it runs on the blocking pool.
+	 */
+	async fn check_password(&self, login: &str, password: String) -> bool {
+		let authenticator = self.context.authenticator.clone();
+		let username = login.to_string();
+		match tokio::task::spawn_blocking(move || authenticator.authenticate(&username, &password)).await {
+			Ok(valid) => {
+				if !valid {
+					error!("Wrong password for user {}", login);
 				}
+				valid
 			}
-			_ => {
-				error!("Unexpected command: {}", msg);
-				None
+			Err(e) => {
+				error!("Failed to check password: {:?}", e);
+				false
 			}
-		};
+		}
 	}
 
-	fn parse_command(&self, msg: &String) -> ClientCommand {
-		debug!("client::parse_command '{}'", msg);
-		if let Some(re) = Regex::new(r"^([[:upper:]]{3,4})( .+)*$").ok() {
-			if let Some(cap) = re.captures(msg.as_str()) {
+	fn parse_command(&self, msg: &[u8]) -> ClientCommand {
+		let line = String::from_utf8_lossy(msg);
+		debug!("client::parse_command '{}'", utils::loggable_command(msg));
+		// The arguments are matched as raw bytes, file names are not always valid UTF-8
+		if let Ok(re) = Regex::new(r"(?s-u)^([[:upper:]]{3,4})( .+)*$") {
+			if let Some(cap) = re.captures(msg) {
 				if let Some(cmd) = cap.get(1) {
+					let cmd = String::from_utf8_lossy(cmd.as_bytes());
 					if let Some(args) = cap.get(2) {
-						return ClientCommand::new_with_args(cmd.as_str(), args.as_str().to_string().trim());
+						return ClientCommand::new(&cmd, Some(args.as_bytes().trim_ascii()));
 					} else {
-						return ClientCommand::new_without_arg(cmd.as_str());
+						return ClientCommand::new(&cmd, None);
 					}
 				}
 			}
 		}
-		error!("failed to parse command: {}", msg);
-		ClientCommand::Unknown(msg.clone())
+		error!("failed to parse command: {}", utils::loggable_command(msg));
+		ClientCommand::Unknown(line.into_owned())
+	}
+
+	/**
+	 * Virtual absolute path of a path sent by the client, None if it goes up above the root directory
+	 */
+	fn virtual_path(&self, arg: &Path) -> Option<PathBuf> {
+		Jail::normalize(self.current_work_directory.as_ref()?, arg)
+	}
+
+	/**
+	 * Storage of the files of the logged user
+	 */
+	fn storage(&self) -> Arc<dyn Storage> {
+		self.storage.clone().expect("storage of a logged user")
+	}
+
+	/**
+	 * Run an operation of the storage on the blocking pool: a slow disk must not stall the other sessions
+	 */
+	async fn with_storage<T, F>(&self, operation: F) -> std::io::Result<T>
+	where T: Send + 'static, F: FnOnce(&dyn Storage) -> std::io::Result<T> + Send + 'static {
+		let storage = self.storage();
+		tokio::task::spawn_blocking(move || operation(storage.as_ref())).await?
+	}
+
+	async fn metadata(&self, path: &Path) -> std::io::Result<Metadata> {
+		let path = path.to_path_buf();
+		self.with_storage(move |storage| storage.metadata(path.as_path())).await
+	}
+
+	/**
+	 * Lines of a listing, built on the blocking pool
+	 */
+	async fn listing<F>(&self, build: F) -> Vec<Vec<u8>>
+	where F: FnOnce(&dyn Storage) -> Vec<Vec<u8>> + Send + 'static {
+		self.with_storage(move |storage| Ok(build(storage))).await.unwrap_or_default()
+	}
+
+	/**
+	 * True if the logged user is not allowed to modify files
+	 */
+	fn is_read_only(&self) -> bool {
+		self.user.as_ref().is_none_or(|user| user.read_only)
+	}
+
+	/**
+	 * Count a file about to be created in the quota, false if the user cannot store one more file
+	 */
+	fn quota_add_file(&self) -> bool {
+		self.quota.as_ref().is_none_or(|quota| quota.add_file())
+	}
+
+	/**
+	 * Give back to the quota the bytes and the files which were removed
+	 */
+	fn quota_release(&self, bytes: u64, files: u64) {
+		if let Some(quota) = self.quota.as_ref() {
+			quota.release(bytes, files);
+		}
 	}
 
 	fn check_word(&self, username: &String) -> bool {
 		let re = Regex::new(r"^([[:word:]]+)$").unwrap();
-		re.captures(username.as_str()).is_some()
+		re.is_match(username.as_bytes())
 	}
 
 	async fn command(&mut self) -> FtpResult<()> {
 		debug!("client::command");
-		let mut msg = self.ctrl_connection.read().await;
-		while msg.is_some() {
-			debug!("Message received: {:?}", msg);
-			match self.parse_command(&msg.as_ref().unwrap()) {
-				ClientCommand::Abor => {
-					self.abor().await?;
-				}
-				ClientCommand::Acct(arg) => {
-					self.acct(arg.as_str()).await?;
-				}
-				ClientCommand::Allo(arg) => {
-					self.allo(arg).await?;
-				}
-				ClientCommand::Appe(arg) => {
-					self.appe(arg).await?;
-				}
-				ClientCommand::CdUp => {
-					self.cdup().await?;
-				}
-				ClientCommand::Cwd(arg) => {
-					self.cwd(arg).await?;
-				}
-				ClientCommand::Dele(arg) => {
-					self.dele(arg).await?;
-				}
-				ClientCommand::Help(arg) => {
-					self.help(arg).await?;
-				}
-				ClientCommand::List(arg) => {
-					if let Some(path) = arg {
-						self.list(path).await?;
-					} else {
-						self.list(self.current_work_directory.as_ref().unwrap().clone()).await?;
-					}
-				}
-				ClientCommand::Mkd(arg) => {
-					self.mkdir(arg).await?;
-				}
-				ClientCommand::Mode => {
-					self.mode().await?;
-				}
-				ClientCommand::Nlist(arg) => {
-					if let Some(path) = arg {
-						self.nlist(path).await?;
-					} else {
-						self.nlist(self.current_work_directory.as_ref().unwrap().clone()).await?;
-					}
-				}
-				ClientCommand::NoOp => {
-					self.noop().await?;
-				}
-				ClientCommand::Pass(_arg) => {
-					// See connect() function
-				}
-				ClientCommand::Pasv => {
-					self.pasv().await?;
-				}
-				ClientCommand::Port(arg) => {
-					self.port(arg).await?;
-				}
-				ClientCommand::Pwd => {
-					self.pwd().await?;
-				}
-				ClientCommand::Quit => {
-					self.ctrl_connection.sendResponse(ServerResponse::ServiceClosingControlConnection, "Connection closed").await?;
-					self.user = None;
-					self.ctrl_connection.close().await;
-					return Ok(());
-				}
-				ClientCommand::Rein => {
-					self.rein().await?;
-				}
-				ClientCommand::Rest(arg) => {
-					self.rest(arg).await?;
-				}
-				ClientCommand::Retr(arg) => {
-					self.retr(arg).await?;
-				}
-				ClientCommand::Rmd(arg) => {
-					self.rmdir(arg).await?;
-				}
-				ClientCommand::Rnfr(arg) => {
-					self.rnfr(arg).await?;
-				}
-				ClientCommand::Rnto(arg) => {
-					self.rnto(arg).await?;
-				}
-				ClientCommand::Site(arg) => {
-					self.site(arg).await?;
-				}
-				ClientCommand::Smnt(arg) => {
-					self.smnt(arg).await?;
-				}
-				ClientCommand::Stat(arg) => {
-					self.stat(arg).await?;
-				}
-				ClientCommand::Stor(arg) => {
-					self.stor(arg).await?;
-				}
-				ClientCommand::Stou(arg) => {
-					self.stou(arg).await?;
-				}
-				ClientCommand::Stru => {
-					self.stru().await?;
-				}
-				ClientCommand::Syst => {
-					self.syst().await?;
+		while let Some(msg) = self.next_command().await {
+			debug!("Message received: {}", utils::loggable_command(&msg));
+			let command = self.parse_command(&msg);
+			if let ClientCommand::Quit = command {
+				self.ctrl_connection.sendResponse(ServerResponse::ServiceClosingControlConnection, "Connection closed").await?;
+				self.user = None;
+				self.ctrl_connection.close().await;
+				return Ok(());
+			}
+			if self.state.is_logged_in() {
+				// The state set by RNFR or REST only applies to this command,
+				// but the data connection may be opened between REST and the transfer
+				let previous = self.state.start_command();
+				let kept = previous.kept_by(&command);
+				self.session_command(command, previous).await?;
+				self.state.restore(kept);
+			} else {
+				self.login_command(command).await?;
+			}
+			if self.state == SessionState::Closing {
+				self.ctrl_connection.close().await;
+				return Ok(());
+			}
+		}
+		Ok(())
+	}
+
+	/**
+	 * The commands received during the last transfer come first
+	 */
+	async fn next_command(&mut self) -> Option<Vec<u8>> {
+		match self.deferred_commands.pop_front() {
+			Some(command) => Some(command),
+			None => self.ctrl_connection.read().await,
+		}
+	}
+
+	/**
+	 * Commands of a logged user, previous is the state before this command
+	 */
+	async fn session_command(&mut self, command: ClientCommand, previous: SessionState) -> FtpResult<()> {
+		if self.context.config.require_tls && !self.protected_data && matches!(command,
+				ClientCommand::Appe(_) | ClientCommand::List(_) | ClientCommand::Mlsd(_) | ClientCommand::Nlist(_)
+				| ClientCommand::Retr(_) | ClientCommand::Stor(_) | ClientCommand::Stou(_)) {
+			// 521 of RFC 4217: the data connection cannot be opened with this PROT setting
+			return self.ctrl_connection.sendResponse(ServerResponse::ProtectionRequired, "Data connection must be protected, use PROT P").await;
+		}
+		match command {
+			ClientCommand::Abor => {
+				self.abor().await?;
+			}
+			ClientCommand::Acct(arg) => {
+				self.acct(arg.as_str()).await?;
+			}
+			ClientCommand::Allo(arg) => {
+				self.allo(arg).await?;
+			}
+			ClientCommand::Auth(arg) => {
+				self.auth(arg).await?;
+			}
+			ClientCommand::Appe(arg) => {
+				self.appe(arg).await?;
+			}
+			ClientCommand::CdUp => {
+				self.cdup().await?;
+			}
+			ClientCommand::Cwd(arg) => {
+				self.cwd(arg).await?;
+			}
+			ClientCommand::Dele(arg) => {
+				self.dele(arg).await?;
+			}
+			ClientCommand::Eprt(arg) => {
+				self.eprt(arg).await?;
+			}
+			ClientCommand::Epsv(arg) => {
+				self.epsv(arg).await?;
+			}
+			ClientCommand::Feat => {
+				self.feat().await?;
+			}
+			ClientCommand::Help(arg) => {
+				self.help(arg).await?;
+			}
+			ClientCommand::List(arg) => {
+				if let Some(path) = arg {
+					self.list(path).await?;
+				} else {
+					self.list(self.current_work_directory.as_ref().unwrap().clone()).await?;
 				}
-				ClientCommand::Type(arg) => {
-					self.transfer_type(arg).await?;
+			}
+			ClientCommand::Mdtm(arg) => {
+				self.mdtm(arg).await?;
+			}
+			ClientCommand::Mkd(arg) => {
+				self.mkdir(arg).await?;
+			}
+			ClientCommand::Mlsd(arg) => {
+				if let Some(path) = arg {
+					self.mlsd(path).await?;
+				} else {
+					self.mlsd(self.current_work_directory.as_ref().unwrap().clone()).await?;
 				}
-				ClientCommand::Unknown(arg) => {
-					self.unknown(arg).await?;
+			}
+			ClientCommand::Mlst(arg) => {
+				if let Some(path) = arg {
+					self.mlst(path).await?;
+				} else {
+					self.mlst(self.current_work_directory.as_ref().unwrap().clone()).await?;
 				}
-				ClientCommand::User(_arg) => {
-					// See connect() function
+			}
+			ClientCommand::Mode => {
+				self.mode().await?;
+			}
+			ClientCommand::Nlist(arg) => {
+				if let Some(path) = arg {
+					self.nlist(path).await?;
+				} else {
+					self.nlist(self.current_work_directory.as_ref().unwrap().clone()).await?;
 				}
 			}
-			msg = self.ctrl_connection.read().await;
+			ClientCommand::NoOp => {
+				self.noop().await?;
+			}
+			ClientCommand::Opts(arg) => {
+				self.opts(arg).await?;
+			}
+			ClientCommand::Pass(_arg) => {
+				self.ctrl_connection.sendResponse(ServerResponse::BadSequenceOfCommands, "Already logged in").await?;
+			}
+			ClientCommand::Pasv => {
+				self.pasv().await?;
+			}
+			ClientCommand::Pbsz(arg) => {
+				self.pbsz(arg).await?;
+			}
+			ClientCommand::Port(arg) => {
+				self.port(arg).await?;
+			}
+			ClientCommand::Prot(arg) => {
+				self.prot(arg).await?;
+			}
+			ClientCommand::Pwd => {
+				self.pwd().await?;
+			}
+			ClientCommand::Quit => {
+				// See command() function
+			}
+			ClientCommand::Rein => {
+				self.rein().await?;
+			}
+			ClientCommand::Rest(arg) => {
+				self.rest(arg).await?;
+			}
+			ClientCommand::Retr(arg) => {
+				self.retr(arg, previous.restart_offset()).await?;
+			}
+			ClientCommand::Rmd(arg) => {
+				self.rmdir(arg).await?;
+			}
+			ClientCommand::Rnfr(arg) => {
+				self.rnfr(arg).await?;
+			}
+			ClientCommand::Rnto(arg) => {
+				self.rnto(arg, previous).await?;
+			}
+			ClientCommand::Site(arg) => {
+				self.site(arg).await?;
+			}
+			ClientCommand::Size(arg) => {
+				self.size(arg).await?;
+			}
+			ClientCommand::Smnt(arg) => {
+				self.smnt(arg).await?;
+			}
+			ClientCommand::Stat(arg) => {
+				self.stat(arg).await?;
+			}
+			ClientCommand::Stor(arg) => {
+				self.stor(arg, previous.restart_offset()).await?;
+			}
+			ClientCommand::Stou(arg) => {
+				self.stou(arg).await?;
+			}
+			ClientCommand::Stru => {
+				self.stru().await?;
+			}
+			ClientCommand::Syst => {
+				self.syst().await?;
+			}
+			ClientCommand::Type(arg) => {
+				self.transfer_type(arg).await?;
+			}
+			ClientCommand::Unknown(arg) => {
+				self.unknown(arg).await?;
+			}
+			ClientCommand::User(_arg) => {
+				self.ctrl_connection.sendResponse(ServerResponse::BadSequenceOfCommands, "Already logged in, use REIN first").await?;
+			}
 		}
 		Ok(())
 	}
 
 	/**
 	 * Cancel the current data transfer
-	 * The function is partially implemented: the function does not support receiving ABOR during a data transfer process !
-	 * To do it a solution is tokio::select to transfer data and listening control socket at the same time.
+	 * ABOR received during a transfer is handled by watch_transfer(), here no transfer is running:
+	 * only the data connection opened for the next transfer is closed.
 	 */
 	async fn abor(&mut self) -> FtpResult<()> {
 		if self.data_connection.is_some() {
@@ -315,130 +551,309 @@ impl Client {
 
 	/**
 	 * Books free space to save data later.
+	 * With a quota, the bytes are reserved for the next upload of the session: other sessions cannot use them.
 	 */
-	async fn allo(&mut self, _arg: u32) -> FtpResult<()> {
-		self.ctrl_connection.sendResponse(ServerResponse::OK, "Not necessary for this site").await
+	async fn allo(&mut self, size: u64) -> FtpResult<()> {
+		let quota = match self.quota.clone() {
+			Some(quota) => quota,
+			None => return self.ctrl_connection.sendResponse(ServerResponse::OK, "Not necessary for this site").await,
+		};
+		// A new ALLO replaces the previous reservation, which is kept if the new one fails
+		let reserved = match self.reservation.as_mut() {
+			Some(reservation) => reservation.resize(size),
+			None => {
+				self.reservation = quota.reserve(size);
+				self.reservation.is_some()
+			}
+		};
+		if reserved {
+			let message = format!("{} bytes reserved for the next upload", size);
+			self.ctrl_connection.sendResponse(ServerResponse::OK, message.as_str()).await
+		} else {
+			self.ctrl_connection.sendResponse(ServerResponse::ExceededStorageAllocation, "Not enough space left in the quota").await
+		}
 	}
 
 	/**
 	 * Same to STOR, but if the file exists, the data are not removed.
 	 */
 	async fn appe(&mut self, arg: PathBuf) -> FtpResult<()> {
+		if self.is_read_only() {
+			return self.ctrl_connection.sendResponse(ServerResponse::PermissionDenied, "Read-only account").await;
+		}
 		if self.data_connection.is_some() {
-			if let Some(path) = utils::get_absolut_path(&arg, self.current_work_directory.as_ref().unwrap()) {
-				return if path.exists() {
-					let file = OpenOptions::new()
-						.write(true)
-						.append(true)
-						.open(path)?;
+			if let Some(path) = self.virtual_path(&arg) {
+				let progress = Progress::new(format!("APPE {}", path.display()), None);
+				// A file created by the upload is 0 byte long
+				let existing = self.metadata(path.as_path()).await.ok();
+				let length = existing.as_ref().map(|metadata| metadata.len).unwrap_or(0);
+				if existing.is_none() && !self.quota_add_file() {
+					return self.ctrl_connection.sendResponse(ServerResponse::ExceededStorageAllocation, "File quota exceeded").await;
+				}
+				let target = path.clone();
+				return if let Ok(file) = self.with_storage(move |storage| storage.append(target.as_path())).await {
 					self.ctrl_connection.sendResponse(ServerResponse::FileStatusOk, "Ok to send data").await?;
-					self.save_data(file).await
+					self.save_data(file, path, length, progress).await
 				} else {
-					if let Ok(file) = File::create(path) {
-						self.ctrl_connection.sendResponse(ServerResponse::FileStatusOk, "Ok to send data").await?;
-						self.save_data(file).await
-					} else {
-						self.ctrl_connection.sendResponse(ServerResponse::PermissionDenied, "Cannot create file").await
+					if existing.is_none() {
+						self.quota_release(0, 1);
 					}
+					self.ctrl_connection.sendResponse(ServerResponse::PermissionDenied, "Cannot create file").await
 				};
 			}
+			return self.ctrl_connection.sendResponse(ServerResponse::PermissionDenied, "Cannot create file").await;
 		}
-		error!("Data connection not initialized");
-		Err(FtpError::DataConnectionError)
+		self.no_data_connection().await
 	}
 
-	async fn cdup(&mut self) -> FtpResult<()> {
-		let path = self.current_work_directory.as_ref().unwrap().parent().unwrap().to_path_buf();
-		if let Ok(_) = fs::read_dir(path.clone()) {
-			return self.ctrl_connection.sendResponse(ServerResponse::RequestedFileActionOkay, "Directory successfully changed").await;
+	/**
+	 * Protect the control connection with TLS, see RFC 4217
+	 */
+	async fn auth(&mut self, arg: String) -> FtpResult<()> {
+		if !arg.eq_ignore_ascii_case("TLS") && !arg.eq_ignore_ascii_case("TLS-C") {
+			return self.ctrl_connection.sendResponse(ServerResponse::CommandNotImplementedForThatParameter, "Only AUTH TLS is supported").await;
+		}
+		if self.ctrl_connection.is_secure() {
+			return self.ctrl_connection.sendResponse(ServerResponse::BadSequenceOfCommands, "Control connection already protected").await;
+		}
+		match self.context.tls_acceptor.clone() {
+			Some(acceptor) => {
+				self.ctrl_connection.sendResponse(ServerResponse::SecurityDataExchangeComplete, "AUTH TLS successful").await?;
+				self.ctrl_connection.upgrade(&acceptor).await
+			}
+			None => {
+				self.ctrl_connection.sendResponse(ServerResponse::NeedUnavailableResource, "TLS is not configured on this server").await
+			}
 		}
-		return self.ctrl_connection.sendResponse(ServerResponse::InvalidParameterOrArgument, path.to_str().unwrap()).await;
+	}
+
+	async fn cdup(&mut self) -> FtpResult<()> {
+		self.cwd(PathBuf::from("..")).await
 	}
 
 	async fn cwd(&mut self, arg: PathBuf) -> FtpResult<()> {
-		let absolut_path = utils::get_absolut_path(&arg, &self.current_work_directory.as_ref().unwrap());
-		if absolut_path.is_some() {
-			let path = absolut_path.unwrap();
-			if let Ok(_) = fs::read_dir(path.clone()) {
-				self.current_work_directory = Some(path);
-				self.ctrl_connection.sendResponse(ServerResponse::RequestedFileActionOkay, "Directory successfully changed").await
-			} else {
-				self.ctrl_connection.sendResponse(ServerResponse::PermissionDenied, "Failed to change directory").await
+		if let Some(virtual_path) = self.virtual_path(&arg) {
+			let directory = virtual_path.clone();
+			if self.with_storage(move |storage| storage.list(directory.as_path())).await.is_ok() {
+				self.current_work_directory = Some(virtual_path);
+				return self.ctrl_connection.sendResponse(ServerResponse::RequestedFileActionOkay, "Directory successfully changed").await;
 			}
-		} else {
-			error!("CWD unknown error, arg: {}", arg.to_str().unwrap());
-			self.ctrl_connection.sendResponse(ServerResponse::InvalidParameterOrArgument, arg.to_str().unwrap()).await
 		}
+		error!("CWD failed, arg: {}", arg.display());
+		self.ctrl_connection.sendResponse(ServerResponse::PermissionDenied, "Failed to change directory").await
 	}
 
 	async fn dele(&mut self, arg: PathBuf) -> FtpResult<()> {
-		info!("Remove file {}", arg.to_str().unwrap());
-		if let Some(path) = utils::get_absolut_path(&arg, &self.current_work_directory.as_ref().unwrap()) {
-			if let Err(e) = fs::remove_file(path.as_path()) {
-				match e.kind() {
-					ErrorKind::PermissionDenied => {
-						return self.ctrl_connection.sendResponse(ServerResponse::PermissionDenied, path.to_str().unwrap()).await;
-					}
-					_ => {
-						return self.ctrl_connection.sendResponse(ServerResponse::BadSequenceOfCommands, path.to_str().unwrap()).await;
-					}
+		if self.is_read_only() {
+			return self.ctrl_connection.sendResponse(ServerResponse::PermissionDenied, "Read-only account").await;
+		}
+		info!("Remove file {}", arg.display());
+		if let Some(name) = self.virtual_path(&arg) {
+			let target = name.clone();
+			let removed = self.with_storage(move |storage| {
+				let length = storage.link_metadata(target.as_path())?.len;
+				storage.remove_file(target.as_path()).map(|()| length)
+			}).await;
+			match removed {
+				Ok(length) => {
+					self.quota_release(length, 1);
+					return self.ctrl_connection.sendResponse(ServerResponse::RequestedFileActionOkay, &name.to_string_lossy()).await;
+				}
+				Err(e) => {
+					error!("DELE failed: {}", e);
+					return self.ctrl_connection.sendResponse(ServerResponse::PermissionDenied, &name.to_string_lossy()).await;
 				}
-			} else {
-				return self.ctrl_connection.sendResponse(ServerResponse::RequestedFileActionOkay, path.to_str().unwrap()).await;
 			}
 		} else {
-			return self.ctrl_connection.sendResponse(ServerResponse::InvalidParameterOrArgument, arg.to_str().unwrap()).await;
+			return self.ctrl_connection.sendResponse(ServerResponse::PermissionDenied, &arg.to_string_lossy()).await;
+		}
+	}
+
+	/**
+	 * Extended PORT, for IPv4 and IPv6 (RFC 2428)
+	 */
+	async fn eprt(&mut self, arg: String) -> FtpResult<()> {
+		if self.epsv_all {
+			return self.ctrl_connection.sendResponse(ServerResponse::BadSequenceOfCommands, "Only EPSV is allowed after EPSV ALL").await;
+		}
+		match utils::parse_eprt(arg.as_str()) {
+			Some(Some(addr)) => self.connect_data_connection(addr, EPRT).await,
+			Some(None) => self.ctrl_connection.sendResponse(ServerResponse::NetworkProtocolNotSupported, "use (1,2)").await,
+			None => self.ctrl_connection.sendResponse(ServerResponse::InvalidParameterOrArgument, "Invalid EPRT argument").await,
+		}
+	}
+
+	/**
+	 * Extended passive mode, for IPv4 and IPv6 (RFC 2428). Only the port is sent to the client.
+	 */
+	async fn epsv(&mut self, arg: Option<String>) -> FtpResult<()> {
+		debug!("Client::epsv");
+		let local_ip = self.ctrl_connection.local_addr()?.ip();
+		let protocol = if local_ip.is_ipv4() { "1" } else { "2" };
+
+		if let Some(arg) = arg {
+			if arg.eq_ignore_ascii_case("ALL") {
+				self.epsv_all = true;
+				return self.ctrl_connection.sendResponse(ServerResponse::OK, "EPSV ALL ok").await;
+			}
+			if arg != protocol {
+				let message = format!("use ({})", protocol);
+				return self.ctrl_connection.sendResponse(ServerResponse::NetworkProtocolNotSupported, message.as_str()).await;
+			}
 		}
+
+		self.transfert_mode = Passive;
+		let listener = match self.passive_listener().await {
+			Some(listener) => listener,
+			None => {
+				return self.ctrl_connection.sendResponse(ServerResponse::CantOpenDataConnection, "No passive port available").await;
+			}
+		};
+		let message = format!("(|||{}|)", listener.local_addr()?.port());
+		self.ctrl_connection.sendResponse(ServerResponse::EnteringExtendedPassiveMode, message.as_str()).await?;
+		self.accept_data_connection(listener).await
 	}
 
-	async fn help(&mut self, _arg: String) -> FtpResult<()> {
-		let mut message: String = "".to_string();
-		message.push_str(" CDUP CWD DELE HELP LIST MKD PASS PASV PORT PWD QUIT RETR RMD SYST USER\n");
-		message.push_str(" RNFR RNTO NOOP NLST STAT\n");
-		self.ctrl_connection.sendResponse(ServerResponse::RecognizedCommandsBeginMessage, message.as_str()).await;
-		self.ctrl_connection.sendResponse(ServerResponse::RecognizedCommandsEndMessage, "214 Help OK").await
+	/**
+	 * List the supported extensions (RFC 2389)
+	 */
+	async fn feat(&mut self) -> FtpResult<()> {
+		let mut reply = Reply::new(ServerResponse::SystemStatus).line("Extensions supported:");
+		if self.context.tls_acceptor.is_some() {
+			reply = reply.line("AUTH TLS");
+		}
+		reply = reply.line("EPRT")
+			.line("EPSV")
+			.line("MDTM")
+			.line("MLST type*;size*;modify*;perm*;unique*;");
+		if self.context.tls_acceptor.is_some() {
+			reply = reply.line("PBSZ").line("PROT");
+		}
+		reply = reply.line("REST STREAM")
+			.line("SIZE")
+			.line("TVFS")
+			.line("UTF8")
+			.line("End");
+		self.ctrl_connection.send_reply(reply).await
+	}
+
+	/**
+	 * List the commands, or give the usage of one command, from the table used by the parser
+	 */
+	async fn help(&mut self, arg: Option<String>) -> FtpResult<()> {
+		if let Some(arg) = arg {
+			return if let Some(command) = Command::find(arg.as_str()) {
+				self.ctrl_connection.send_reply(Reply::new(ServerResponse::HelpMessage).line(format!("Syntax: {}", command.usage))).await
+			} else {
+				self.ctrl_connection.sendResponse(ServerResponse::InvalidParameterOrArgument, "Unknown command").await
+			};
+		}
+
+		let mut reply = Reply::new(ServerResponse::HelpMessage).line("The following commands are recognized.");
+		for commands in COMMANDS.chunks(8) {
+			let names: Vec<&str> = commands.iter().map(|command| command.name).collect();
+			reply = reply.line(names.join(" "));
+		}
+		reply = reply.line("Help OK");
+		self.ctrl_connection.send_reply(reply).await
 	}
 
 	async fn list(&mut self, arg: PathBuf) -> FtpResult<()> {
 		if self.data_connection.is_some() {
-			if let Some(path) = utils::get_absolut_path(&arg, self.current_work_directory.as_ref().unwrap()) {
-				self.ctrl_connection.sendResponse(ServerResponse::FileStatusOk, "Here comes the directory listing").await?;
+			if let Some(path) = self.virtual_path(&arg) {
+				let command = format!("LIST {}", path.display());
+				let lines = self.listing(move |storage| utils::get_ls(storage, path.as_path())).await;
+				self.send_listing(lines, command).await
+			} else {
+				self.ctrl_connection.sendResponse(ServerResponse::PermissionDenied, "Failed to list directory").await
+			}
+		} else {
+			self.no_data_connection().await
+		}
+	}
 
-				if self.send_data(utils::get_ls(path.as_path())).await.is_ok() {
-					self.ctrl_connection.sendResponse(ServerResponse::ClosingDataConnection, "Directory send OK").await?;
+	/**
+	 * Modification time of a file (RFC 3659)
+	 */
+	async fn mdtm(&mut self, arg: PathBuf) -> FtpResult<()> {
+		if let Some(path) = self.virtual_path(&arg) {
+			if let Ok(metadata) = self.metadata(path.as_path()).await {
+				if metadata.is_file {
+					let modification: DateTime<Utc> = DateTime::from(metadata.modified);
+					let message = modification.format(utils::MACHINE_TIME_FORMAT).to_string();
+					return self.ctrl_connection.sendResponse(ServerResponse::FileStatus, message.as_str()).await;
 				}
 			}
-
-			Ok(())
-		} else {
-			error!("Data connection not initialized");
-			Err(FtpError::DataConnectionError)
 		}
+		self.ctrl_connection.sendResponse(ServerResponse::PermissionDenied, "Could not get file modification time").await
 	}
 
 	async fn mkdir(&mut self, arg: PathBuf) -> FtpResult<()> {
-		info!("Create directory {}", arg.to_str().unwrap());
-		if let Some(path) = utils::get_absolut_path(&arg, &self.current_work_directory.as_ref().unwrap()) {
-			if let Err(e) = fs::create_dir(path.as_path()) {
+		if self.is_read_only() {
+			return self.ctrl_connection.sendResponse(ServerResponse::PermissionDenied, "Read-only account").await;
+		}
+		info!("Create directory {}", arg.display());
+		if let Some(name) = self.virtual_path(&arg) {
+			let target = name.clone();
+			if let Err(e) = self.with_storage(move |storage| storage.create_dir(target.as_path())).await {
 				match e.kind() {
 					ErrorKind::AlreadyExists => {
-						self.ctrl_connection.sendResponse(ServerResponse::AlreadyExists, path.to_str().unwrap()).await
-					}
-					ErrorKind::PermissionDenied => {
-						self.ctrl_connection.sendResponse(ServerResponse::PermissionDenied, path.to_str().unwrap()).await
+						let message = format!("{}: already exists", name.to_string_lossy());
+						self.ctrl_connection.sendResponse(ServerResponse::PermissionDenied, message.as_str()).await
 					}
 					_ => {
-						self.ctrl_connection.sendResponse(ServerResponse::BadSequenceOfCommands, path.to_str().unwrap()).await
+						error!("MKD failed: {}", e);
+						self.ctrl_connection.sendResponse(ServerResponse::PermissionDenied, &name.to_string_lossy()).await
 					}
 				}
 			} else {
-				self.ctrl_connection.sendResponse(ServerResponse::PathNameCreated, path.to_str().unwrap()).await
+				self.ctrl_connection.sendResponse(ServerResponse::PathNameCreated, &name.to_string_lossy()).await
 			}
 		} else {
-			self.ctrl_connection.sendResponse(ServerResponse::InvalidParameterOrArgument, arg.to_str().unwrap()).await
+			self.ctrl_connection.sendResponse(ServerResponse::PermissionDenied, &arg.to_string_lossy()).await
 		}
 	}
 
+	/**
+	 * Machine-readable listing of a directory (RFC 3659), sent through the data connection
+	 */
+	async fn mlsd(&mut self, arg: PathBuf) -> FtpResult<()> {
+		if self.data_connection.is_some() {
+			if let Some(path) = self.virtual_path(&arg) {
+				if !self.metadata(path.as_path()).await.is_ok_and(|metadata| metadata.is_dir) {
+					return self.ctrl_connection.sendResponse(ServerResponse::InvalidParameterOrArgument, "Not a directory").await;
+				}
+				let command = format!("MLSD {}", path.display());
+				let read_only = self.is_read_only();
+				let lines = self.listing(move |storage| utils::get_mlsd(storage, path.as_path(), read_only)).await;
+				self.send_listing(lines, command).await
+			} else {
+				self.ctrl_connection.sendResponse(ServerResponse::PermissionDenied, "Failed to list directory").await
+			}
+		} else {
+			self.no_data_connection().await
+		}
+	}
+
+	/**
+	 * Machine-readable facts of a single file (RFC 3659), sent on the control connection
+	 */
+	async fn mlst(&mut self, arg: PathBuf) -> FtpResult<()> {
+		if let Some(path) = self.virtual_path(&arg) {
+			let read_only = self.is_read_only();
+			let target = path.clone();
+			if let Ok(Ok(facts)) = self.with_storage(move |storage| Ok(utils::get_mlst(storage, target.as_path(), read_only))).await {
+				let name = path.as_os_str().as_bytes();
+				let reply = Reply::new(ServerResponse::RequestedFileActionOkay)
+					.line([b"Listing ", name].concat())
+					.line([facts.as_bytes(), b" ", name].concat())
+					.line("End");
+				return self.ctrl_connection.send_reply(reply).await;
+			}
+		}
+		self.ctrl_connection.sendResponse(ServerResponse::PermissionDenied, "No such file or directory").await
+	}
+
 	/**
 	 * Set transfer mode
 	 */
@@ -452,218 +867,538 @@ impl Client {
 
 	async fn nlist(&mut self, arg: PathBuf) -> FtpResult<()> {
 		if self.data_connection.is_some() {
-			if let Some(path) = utils::get_absolut_path(&arg, self.current_work_directory.as_ref().unwrap()) {
-				self.ctrl_connection.sendResponse(ServerResponse::FileStatusOk, "Here comes the directory listing").await?;
-				self.send_data(utils::get_nls(path.as_path(), path.as_path().to_str().unwrap())).await?;
-				self.ctrl_connection.sendResponse(ServerResponse::ClosingDataConnection, "Directory send OK").await?;
+			if let Some(path) = self.virtual_path(&arg) {
+				let command = format!("NLST {}", path.display());
+				let lines = self.listing(move |storage| utils::get_nls(storage, path.as_path())).await;
+				self.send_listing(lines, command).await
+			} else {
+				self.ctrl_connection.sendResponse(ServerResponse::PermissionDenied, "Failed to list directory").await
 			}
-			Ok(())
 		} else {
-			error!("Data connection not initialized");
-			Err(FtpError::DataConnectionError)
+			self.no_data_connection().await
+		}
+	}
+
+	/**
+	 * Options of a command (RFC 2389). Paths are always sent as they are, so UTF8 is always on.
+	 */
+	async fn opts(&mut self, arg: String) -> FtpResult<()> {
+		let mut args = arg.split_whitespace();
+		match (args.next(), args.next()) {
+			(Some(option), Some(value)) if option.eq_ignore_ascii_case("UTF8") && value.eq_ignore_ascii_case("ON") => {
+				self.ctrl_connection.sendResponse(ServerResponse::OK, "Always in UTF8 mode").await
+			}
+			_ => {
+				self.ctrl_connection.sendResponse(ServerResponse::InvalidParameterOrArgument, "Option not understood").await
+			}
 		}
 	}
 
 	async fn pasv(&mut self) -> FtpResult<()> {
 		debug!("Client::pasv");
+		if self.epsv_all {
+			return self.ctrl_connection.sendResponse(ServerResponse::BadSequenceOfCommands, "Only EPSV is allowed after EPSV ALL").await;
+		}
+
+		// PASV can only send an IPv4 address
+		let ip = match self.ctrl_connection.local_addr()?.ip() {
+			IpAddr::V4(ip) => Some(ip),
+			IpAddr::V6(ip) => ip.to_ipv4_mapped(),
+		};
+		let ip = match self.context.config.passive_address.or(ip) {
+			Some(ip) => ip,
+			None => {
+				return self.ctrl_connection.sendResponse(ServerResponse::CantOpenDataConnection, "PASV is IPv4 only, use EPSV").await;
+			}
+		};
 
 		self.transfert_mode = Passive;
+		let listener = match self.passive_listener().await {
+			Some(listener) => listener,
+			None => {
+				return self.ctrl_connection.sendResponse(ServerResponse::CantOpenDataConnection, "No passive port available").await;
+			}
+		};
+		let message = utils::get_addr_msg(ip, listener.local_addr()?.port());
+		self.ctrl_connection.sendResponse(ServerResponse::EnteringPassiveMode, message.as_str()).await?;
+		self.accept_data_connection(listener).await
+	}
 
-		let port: u16 = pick_unused_port().expect("No ports free");
-		let listener = TcpListener::bind(format!("{}:{}", ADDR, port)).await?;
-		let socket_addr = listener.local_addr()?;
-		info!("Server listening data on {:?}", socket_addr);
+	/**
+	 * Listen for a data connection on the local address used by the control connection
+	 */
+	async fn passive_listener(&self) -> Option<TcpListener> {
+		let ip = self.ctrl_connection.local_addr().ok()?.ip();
+		let ports = match self.context.config.passive_ports {
+			Some([first, last]) => first..=last,
+			None => {
+				let port = pick_unused_port()?;
+				port..=port
+			}
+		};
+		for port in ports {
+			if let Ok(listener) = TcpListener::bind(SocketAddr::new(ip, port)).await {
+				info!("Server listening data on {:?}", listener.local_addr().ok()?);
+				return Some(listener);
+			}
+		}
+		error!("No passive port available");
+		None
+	}
 
-		self.ctrl_connection.sendResponse(ServerResponse::EnteringPassiveMode, utils::get_addr_msg(socket_addr).as_str()).await?;
+	/**
+	 * Wait for the client to open the data connection, at most data_timeout
+	 */
+	async fn accept_data_connection(&mut self, listener: TcpListener) -> FtpResult<()> {
+		match tokio::time::timeout(self.context.data_timeout(), listener.accept()).await {
+			Ok(Ok((stream, addr))) => {
+				info!("Data connection open with addr {:?}", addr);
+				self.data_connection = Some(Connection::new(stream, self.context.data_timeout()));
+				Ok(())
+			}
+			Ok(Err(e)) => {
+				error!("Failed to accept data connection: {}", e);
+				self.ctrl_connection.sendResponse(ServerResponse::CantOpenDataConnection, "Failed to accept data connection").await
+			}
+			Err(_) => {
+				error!("No data connection after {:?}", self.context.data_timeout());
+				self.ctrl_connection.sendResponse(ServerResponse::CantOpenDataConnection, "Data connection timed out").await
+			}
+		}
+	}
 
-		let (stream, addr) = listener.accept().await?;
-		info!("Data connection open with addr {:?}", addr);
-		let (rx, tx) = stream.into_split();
-		self.data_connection = Some(Connection::new(rx, tx));
+	/**
+	 * Open the data connection with the address given by PORT or EPRT
+	 */
+	async fn connect_data_connection(&mut self, addr: SocketAddr, command: &str) -> FtpResult<()> {
+		let client = self.ctrl_connection.peer_addr()?.ip();
+		if !access::is_data_target_allowed(&self.context.config.port_rules, client, addr.ip()) {
+			info!("{} to {} denied by port_rules", command, addr);
+			return self.ctrl_connection.sendResponse(ServerResponse::RequestDeniedForPolicyReasons, "Address not allowed for data connections").await;
+		}
+		match TcpStream::connect(addr).await {
+			Ok(socket) => {
+				self.transfert_mode = Active;
+				self.data_connection = Some(Connection::new(socket, self.context.data_timeout()));
+				let message = format!("{} command successful. Consider using EPSV", command);
+				self.ctrl_connection.sendResponse(ServerResponse::OK, message.as_str()).await
+			}
+			Err(e) => {
+				error!("Failed to connect to {:?}: {}", addr, e);
+				self.ctrl_connection.sendResponse(ServerResponse::CantOpenDataConnection, "Failed to connect").await
+			}
+		}
+	}
 
-		Ok(())
+	/**
+	 * Protection buffer size, always 0 with TLS
+	 */
+	async fn pbsz(&mut self, _arg: String) -> FtpResult<()> {
+		if !self.ctrl_connection.is_secure() {
+			return self.ctrl_connection.sendResponse(ServerResponse::BadSequenceOfCommands, "AUTH TLS first").await;
+		}
+		self.pbsz_done = true;
+		self.ctrl_connection.sendResponse(ServerResponse::OK, "PBSZ=0").await
 	}
 
 	async fn port(&mut self, arg: String) -> FtpResult<()> {
+		if self.epsv_all {
+			return self.ctrl_connection.sendResponse(ServerResponse::BadSequenceOfCommands, "Only EPSV is allowed after EPSV ALL").await;
+		}
 		if let Some(addr) = utils::parse_port(arg) {
-			let socket = TcpStream::connect(SocketAddr::new(addr.0, addr.1)).await?;
-			let (rx, tx) = socket.into_split();
-			self.data_connection = Some(Connection::new(rx, tx));
-
-			self.ctrl_connection.sendResponse(ServerResponse::OK, "PORT command successful. Consider using PASV").await?;
-			Ok(())
+			self.connect_data_connection(SocketAddr::new(addr.0, addr.1), PORT).await
 		} else {
-			Err(FtpError::DataConnectionError)
+			self.ctrl_connection.sendResponse(ServerResponse::InvalidParameterOrArgument, "Invalid PORT argument").await
+		}
+	}
+
+	/**
+	 * Data channel protection level: C (clear) or P (private, with TLS)
+	 */
+	async fn prot(&mut self, arg: String) -> FtpResult<()> {
+		if !self.pbsz_done {
+			return self.ctrl_connection.sendResponse(ServerResponse::BadSequenceOfCommands, "PBSZ first").await;
+		}
+		match arg.to_uppercase().as_str() {
+			"P" => {
+				self.protected_data = true;
+				self.ctrl_connection.sendResponse(ServerResponse::OK, "Data connection will be protected").await
+			}
+			"C" => {
+				if self.context.config.require_tls {
+					return self.ctrl_connection.sendResponse(ServerResponse::RequestDeniedForPolicyReasons, "Data connection must be protected").await;
+				}
+				self.protected_data = false;
+				self.ctrl_connection.sendResponse(ServerResponse::OK, "Data connection will be plain text").await
+			}
+			"S" | "E" => {
+				self.ctrl_connection.sendResponse(ServerResponse::ProtectionLevelNotSupported, "Only C and P are supported").await
+			}
+			_ => {
+				self.ctrl_connection.sendResponse(ServerResponse::CommandNotImplementedForThatParameter, "Unknown protection level").await
+			}
 		}
 	}
 
 	async fn pwd(&mut self) -> FtpResult<()> {
-		let message = format!("{} is the current directory", self.current_work_directory.as_ref().unwrap().to_str().unwrap());
-		self.ctrl_connection.sendResponse(ServerResponse::PathNameCreated, message.as_str()).await
+		let directory = self.current_work_directory.as_ref().unwrap().as_os_str().as_bytes();
+		let reply = Reply::new(ServerResponse::PathNameCreated).line([b"\"", directory, b"\" is the current directory"].concat());
+		self.ctrl_connection.send_reply(reply).await
 	}
 
 	/**
-	 * Restart a user connection
+	 * Reinitialize the session: log out the user and forget the parameters of the transfers.
+	 * The TLS protection of the connections is kept.
 	 */
 	async fn rein(&mut self) -> FtpResult<()> {
-		self.ctrl_connection.sendResponse(ServerResponse::CommandNotImplemented, "").await
+		if let Some(mut data_connection) = self.data_connection.take() {
+			data_connection.close().await;
+		}
+		if let Some(user) = self.user.take() {
+			info!("Logout {}", user.name);
+		}
+		self.login = None;
+		self.storage = None;
+		self.quota = None;
+		self.reservation = None;
+		self.download_throttle = Throttle::default();
+		self.upload_throttle = Throttle::default();
+		self.current_work_directory = None;
+		self.transfert_mode = Active;
+		self.transfert_type = TransferType::Ascii;
+		self.epsv_all = false;
+		self.state = SessionState::AwaitingUser;
+		self.ctrl_connection.sendResponse(ServerResponse::ServiceReadyForNewUser, "Ready for new user").await
 	}
 
 	/**
 	 * Restart a data transfer process
 	 */
-	async fn rest(&mut self, _arg: String) -> FtpResult<()> {
-		self.ctrl_connection.sendResponse(ServerResponse::CommandNotImplemented, "").await
+	async fn rest(&mut self, arg: String) -> FtpResult<()> {
+		// In ASCII mode the client counts the bytes it received with CRLF end of lines, not the bytes of the file
+		if self.transfert_type == TransferType::Ascii {
+			return self.ctrl_connection.sendResponse(ServerResponse::CommandNotImplementedForThatParameter, "REST not supported in ASCII mode, use TYPE I").await;
+		}
+		match arg.parse::<u64>() {
+			Ok(offset) => {
+				self.state = SessionState::RestartPending(offset);
+				let message = format!("Restarting at {}. Send RETR or STOR to initiate transfer", offset);
+				self.ctrl_connection.sendResponse(ServerResponse::RequestedFileActionPendingFurtherInformation, message.as_str()).await
+			}
+			Err(_) => {
+				self.ctrl_connection.sendResponse(ServerResponse::InvalidParameterOrArgument, "Invalid restart position").await
+			}
+		}
 	}
 
-	async fn retr(&mut self, arg: PathBuf) -> FtpResult<()> {
+	async fn retr(&mut self, arg: PathBuf, offset: u64) -> FtpResult<()> {
 		if self.data_connection.is_some() {
-			if let Some(path) = utils::get_absolut_path(&arg, self.current_work_directory.as_ref().unwrap()) {
-				if let Some(data) = utils::get_file(path.as_path()) {
-					self.ctrl_connection.sendResponse(ServerResponse::FileStatusOk, "Start transfer file").await?;
-
-					if self.send_data(vec![String::from_utf8(data).unwrap()]).await.is_ok() {
-						return self.ctrl_connection.sendResponse(ServerResponse::ClosingDataConnection, "Transfer complete").await;
+			if let Some(path) = self.virtual_path(&arg) {
+				if let Some(metadata) = self.metadata(path.as_path()).await.ok().filter(|metadata| metadata.is_file) {
+					if offset > metadata.len {
+						return self.ctrl_connection.sendResponse(ServerResponse::InvalidParameterOrArgument, "Restart position beyond end of file").await;
 					}
+					let progress = Progress::new(format!("RETR {}", path.display()), Some(metadata.len - offset));
+					let result = if let Some(file) = self.zero_copy_file(path.as_path()).await {
+						self.ctrl_connection.sendResponse(ServerResponse::FileStatusOk, "Start transfer file").await?;
+						self.send_local_file(file, offset, metadata.len, progress).await
+					} else {
+						let target = path.clone();
+						match self.with_storage(move |storage| storage.open_read(target.as_path(), offset)).await {
+							Ok(file) => {
+								self.ctrl_connection.sendResponse(ServerResponse::FileStatusOk, "Start transfer file").await?;
+								self.send_file(file, progress).await
+							}
+							Err(e) => {
+								error!("Failed to open file {:?}: {}", path, e);
+								return self.ctrl_connection.sendResponse(ServerResponse::PermissionDenied, "Failed to open file").await;
+							}
+						}
+					};
+					return match result {
+						Ok(()) => self.ctrl_connection.sendResponse(ServerResponse::ClosingDataConnection, "Transfer complete").await,
+						Err(e) => self.transfer_failed(e).await,
+					};
 				}
 			}
 			self.ctrl_connection.sendResponse(ServerResponse::PermissionDenied, "Failed to open file").await
 		} else {
-			error!("Data connection not initialized");
-			Err(FtpError::DataConnectionError)
+			self.no_data_connection().await
 		}
 	}
 
 	async fn rmdir(&mut self, arg: PathBuf) -> FtpResult<()> {
-		info!("Remove directory {}", arg.to_str().unwrap());
-		if let Some(path) = utils::get_absolut_path(&arg, &self.current_work_directory.as_ref().unwrap()) {
-			if let Err(e) = fs::remove_dir(path.as_path()) {
-				match e.kind() {
-					ErrorKind::PermissionDenied => {
-						self.ctrl_connection.sendResponse(ServerResponse::PermissionDenied, path.to_str().unwrap()).await
-					}
-					_ => {
-						error!("RMDIR unknown error: {}", e);
-						self.ctrl_connection.sendResponse(ServerResponse::BadSequenceOfCommands, path.to_str().unwrap()).await
-					}
-				}
+		if self.is_read_only() {
+			return self.ctrl_connection.sendResponse(ServerResponse::PermissionDenied, "Read-only account").await;
+		}
+		info!("Remove directory {}", arg.display());
+		if let Some(name) = self.virtual_path(&arg) {
+			if name == Path::new("/") {
+				return self.ctrl_connection.sendResponse(ServerResponse::PermissionDenied, "Cannot remove the root directory").await;
+			}
+			let target = name.clone();
+			if let Err(e) = self.with_storage(move |storage| storage.remove_dir(target.as_path())).await {
+				error!("RMD failed: {}", e);
+				self.ctrl_connection.sendResponse(ServerResponse::PermissionDenied, &name.to_string_lossy()).await
 			} else {
-				self.ctrl_connection.sendResponse(ServerResponse::RequestedFileActionOkay, path.to_str().unwrap()).await
+				self.ctrl_connection.sendResponse(ServerResponse::RequestedFileActionOkay, &name.to_string_lossy()).await
 			}
 		} else {
-			error!("RMDIR unknown error, arg: {}", arg.to_str().unwrap());
-			self.ctrl_connection.sendResponse(ServerResponse::InvalidParameterOrArgument, arg.to_str().unwrap()).await
+			error!("RMDIR path refused, arg: {}", arg.display());
+			self.ctrl_connection.sendResponse(ServerResponse::PermissionDenied, &arg.to_string_lossy()).await
 		}
 	}
 
 	async fn rnfr(&mut self, arg: PathBuf) -> FtpResult<()> {
-		if let Some(path) = utils::get_absolut_path(&arg, self.current_work_directory.as_ref().unwrap()) {
-			if path.exists() {
-				self.current_working_path = Some(path);
+		if self.is_read_only() {
+			return self.ctrl_connection.sendResponse(ServerResponse::PermissionDenied, "Read-only account").await;
+		}
+		if let Some(path) = self.virtual_path(&arg) {
+			if self.metadata(path.as_path()).await.is_ok() {
+				self.state = SessionState::RenamePending(path);
 				return self.ctrl_connection.sendResponse(ServerResponse::RequestedFileActionPendingFurtherInformation, "Ready for RNTO").await;
 			}
 		}
 		self.ctrl_connection.sendResponse(ServerResponse::PermissionDenied, "command failed").await
 	}
 
-	async fn rnto(&mut self, arg: PathBuf) -> FtpResult<()> {
-		if let Some(origin_path) = self.current_working_path.as_ref() {
-			if let Some(working_path) = utils::get_absolut_path(&arg, self.current_work_directory.as_ref().unwrap()) {
-				if fs::rename(origin_path, working_path).is_ok() {
-					self.current_working_path = None;
+	async fn rnto(&mut self, arg: PathBuf, previous: SessionState) -> FtpResult<()> {
+		if self.is_read_only() {
+			return self.ctrl_connection.sendResponse(ServerResponse::PermissionDenied, "Read-only account").await;
+		}
+		if let Some(origin_path) = previous.rename_origin() {
+			if let Some(working_path) = self.virtual_path(&arg) {
+				// A file replaced by the rename is removed from the quota
+				let renamed = self.with_storage(move |storage| {
+					let replaced = storage.link_metadata(working_path.as_path()).ok()
+						.filter(|metadata| metadata.is_file && origin_path != working_path);
+					storage.rename(origin_path.as_path(), working_path.as_path()).map(|()| replaced)
+				}).await;
+				if let Ok(replaced) = renamed {
+					if let Some(metadata) = replaced {
+						self.quota_release(metadata.len, 1);
+					}
 					return self.ctrl_connection.sendResponse(ServerResponse::RequestedFileActionOkay, "Rename successful").await;
 				}
 			}
+			return self.ctrl_connection.sendResponse(ServerResponse::PermissionDenied, "command failed").await;
 		}
-		self.ctrl_connection.sendResponse(ServerResponse::PermissionDenied, "command failed").await
+		self.ctrl_connection.sendResponse(ServerResponse::BadSequenceOfCommands, "RNFR required first").await
 	}
 
 	/**
-	 * Specific commands for this site
+	 * Specific commands for this site:
+	 * - SITE QUOTA: usage and limits of the logged user
+	 * - SITE BANS: addresses banned after failed logins (admin)
+	 * - SITE UNBAN <address>: lift a ban (admin)
 	 */
 	async fn site(&mut self, arg: String) -> FtpResult<()> {
-		self.ctrl_connection.sendResponse(ServerResponse::CommandNotImplemented, arg.as_str()).await
+		let mut words = arg.split(' ').filter(|word| !word.is_empty());
+		let command = words.next().unwrap_or_default().to_ascii_uppercase();
+		match command.as_str() {
+			"QUOTA" => self.site_quota().await,
+			"BANS" | "UNBAN" if !self.user.as_ref().is_some_and(|user| user.admin) => {
+				self.ctrl_connection.sendResponse(ServerResponse::PermissionDenied, "Reserved to administrators").await
+			}
+			"BANS" => self.site_bans().await,
+			"UNBAN" => self.site_unban(words.next().unwrap_or_default()).await,
+			_ => self.ctrl_connection.sendResponse(ServerResponse::CommandNotImplemented, arg.as_str()).await,
+		}
+	}
+
+	async fn site_bans(&mut self) -> FtpResult<()> {
+		let bans = self.context.login_guard.bans();
+		if bans.is_empty() {
+			return self.ctrl_connection.sendResponse(ServerResponse::OK, "No banned address").await;
+		}
+		let mut reply = Reply::new(ServerResponse::OK).line("Banned addresses");
+		for (ip, remaining) in bans {
+			reply = reply.line(format!("{} for {} more seconds", ip, remaining.as_secs()));
+		}
+		self.ctrl_connection.send_reply(reply.line("End of bans")).await
+	}
+
+	async fn site_unban(&mut self, arg: &str) -> FtpResult<()> {
+		let ip = match arg.parse::<IpAddr>() {
+			Ok(ip) => ip.to_canonical(),
+			Err(_) => return self.ctrl_connection.sendResponse(ServerResponse::InvalidParameterOrArgument, "SITE UNBAN <address>").await,
+		};
+		if self.context.login_guard.unban(ip) {
+			info!("Ban of {} lifted by {}", ip, self.user.as_ref().unwrap().name);
+			self.ctrl_connection.sendResponse(ServerResponse::OK, format!("Ban of {} lifted", ip).as_str()).await
+		} else {
+			self.ctrl_connection.sendResponse(ServerResponse::PermissionDenied, format!("{} is not banned", ip).as_str()).await
+		}
+	}
+
+	async fn site_quota(&mut self) -> FtpResult<()> {
+		let name = self.user.as_ref().unwrap().name.clone();
+		match self.quota.as_ref() {
+			Some(quota) => {
+				let reply = quota.reply(name.as_str());
+				self.ctrl_connection.send_reply(reply).await
+			}
+			None => self.ctrl_connection.sendResponse(ServerResponse::OK, format!("No quota for {}", name).as_str()).await,
+		}
+	}
+
+	/**
+	 * Size of a file (RFC 3659), as it would be transferred with the current TYPE
+	 */
+	async fn size(&mut self, arg: PathBuf) -> FtpResult<()> {
+		if let Some(path) = self.virtual_path(&arg) {
+			let size = match self.metadata(path.as_path()).await {
+				Ok(metadata) if metadata.is_file => {
+					match self.transfert_type {
+						// The whole file is read to count its end of lines
+						TransferType::Ascii => match self.with_storage(move |storage| storage.open_read(path.as_path(), 0)).await {
+							Ok(file) => ascii::network_size(file).await.ok(),
+							Err(_) => None,
+						},
+						_ => Some(metadata.len),
+					}
+				}
+				_ => None,
+			};
+			if let Some(size) = size {
+				return self.ctrl_connection.sendResponse(ServerResponse::FileStatus, size.to_string().as_str()).await;
+			}
+		}
+		self.ctrl_connection.sendResponse(ServerResponse::PermissionDenied, "Could not get file size").await
 	}
 
 	/**
 	 * Mount a file system
 	 */
 	async fn smnt(&mut self, arg: PathBuf) -> FtpResult<()> {
-		self.ctrl_connection.sendResponse(ServerResponse::CommandNotImplemented, arg.to_str().unwrap()).await
-	}
-
-	async fn stat(&mut self, arg: PathBuf) -> FtpResult<()> {
-		let mut message = "".to_string();
-
-		if arg.as_path().to_str().unwrap().is_empty() {
-			message.push_str(format!("{} Server status \r\n", ServerResponse::SystemStatus.to_string()).as_str());
-			message.push_str(format!("   Connected to {} \r\n", ADDR).as_str());
-			message.push_str(format!("   Logged in as {} \r\n", self.user.as_ref().unwrap().name().to_str().unwrap()).as_str());
-			message.push_str(format!("   Type {} \r\n", self.transfert_type).as_str());
-			message.push_str("   No session bandwidth limit\r\n");
-			message.push_str(format!("   Session timeout in seconds is {} \r\n", connection::TIME_OUT).as_str());
-			message.push_str("   Control connection is plain text\r\n");
-			message.push_str("   Data connection will be plain text\r\n");
-			message.push_str(format!("   At session startup, client count was {} \r\n", self.id).as_str());
-			message.push_str("   FTP server version 0.0.1\r\n");
-			message.push_str(format!("{} End of status \r\n", ServerResponse::SystemStatus.to_string()).as_str());
-		} else {
-			if let Some(path) = utils::get_absolut_path(&arg, self.current_work_directory.as_ref().unwrap()) {
-				message.push_str(format!("{} Status follows \r\n", ServerResponse::FileStatus.to_string()).as_str());
-				for msg in utils::get_ls(path.as_path()) {
-					message.push_str(format!("{}\r\n", msg).as_str());
+		self.ctrl_connection.sendResponse(ServerResponse::CommandNotImplemented, &arg.to_string_lossy()).await
+	}
+
+	async fn stat(&mut self, arg: Option<PathBuf>) -> FtpResult<()> {
+		let reply;
+
+		if let Some(arg) = arg {
+			if let Some(path) = self.virtual_path(&arg) {
+				let mut file_status = Reply::new(ServerResponse::FileStatus).line("Status follows");
+				for msg in self.listing(move |storage| utils::get_ls(storage, path.as_path())).await {
+					file_status = file_status.line(msg);
 				}
-				message.push_str("End of status");
+				reply = file_status.line("End of status");
+			} else {
+				return self.ctrl_connection.sendResponse(ServerResponse::PermissionDenied, "Cannot get status").await;
 			}
+		} else {
+			reply = Reply::new(ServerResponse::SystemStatus)
+				.line("Server status")
+				.line(format!("Connected to {}", self.ctrl_connection.peer_addr()?.ip()))
+				.line(format!("Logged in as {}", self.user.as_ref().unwrap().name))
+				.line(format!("Type {}", self.transfert_type))
+				.line(self.download_throttle.status(Direction::Download))
+				.line(self.upload_throttle.status(Direction::Upload))
+				.line(format!("Session timeout in seconds is {}", self.context.config.idle_timeout))
+				.line(if self.ctrl_connection.is_secure() {
+					"Control connection is protected by TLS"
+				} else {
+					"Control connection is plain text"
+				})
+				.line(if self.protected_data {
+					"Data connection will be protected by TLS"
+				} else {
+					"Data connection will be plain text"
+				})
+				.line(format!("At session startup, client count was {}", self.id))
+				.line("FTP server version 0.0.1")
+				.line("End of status");
 		}
-		self.ctrl_connection.sendResponse(ServerResponse::FileStatus, message.as_str()).await
+		self.ctrl_connection.send_reply(reply).await
 	}
 
 	/**
 	 * Save data in a file. The data are sent through the data socket.
 	 * If the file exists, the data are removed.
 	 */
-	async fn stor(&mut self, arg: PathBuf) -> FtpResult<()> {
+	async fn stor(&mut self, arg: PathBuf, offset: u64) -> FtpResult<()> {
+		if self.is_read_only() {
+			return self.ctrl_connection.sendResponse(ServerResponse::PermissionDenied, "Read-only account").await;
+		}
 		if self.data_connection.is_some() {
-			if let Some(path) = utils::get_absolut_path(&arg, self.current_work_directory.as_ref().unwrap()) {
-				return if let Ok(file) = File::create(path) {
+			if let Some(path) = self.virtual_path(&arg) {
+				let progress = Progress::new(format!("STOR {}", path.display()), None);
+				// STOR replaces a symbolic link, a restarted STOR writes to its target
+				let target = path.clone();
+				let existing = self.with_storage(move |storage| match offset {
+					0 => storage.link_metadata(target.as_path()),
+					_ => storage.metadata(target.as_path()),
+				}).await.ok().filter(|metadata| metadata.is_file);
+				if offset > 0 {
+					match existing.as_ref() {
+						Some(metadata) => {
+							if offset > metadata.len {
+								return self.ctrl_connection.sendResponse(ServerResponse::InvalidParameterOrArgument, "Restart position beyond end of file").await;
+							}
+						}
+						None => return self.ctrl_connection.sendResponse(ServerResponse::PermissionDenied, "Cannot open file").await,
+					}
+				}
+				if existing.is_none() && !self.quota_add_file() {
+					return self.ctrl_connection.sendResponse(ServerResponse::ExceededStorageAllocation, "File quota exceeded").await;
+				}
+				// With REST, what was received after the restart marker is dropped, then the writing resumes from it.
+				let target = path.clone();
+				return if let Ok(file) = self.with_storage(move |storage| storage.open_write(target.as_path(), offset)).await {
+					// The bytes cut from an existing file are given back to the quota
+					if let Some(metadata) = existing {
+						self.quota_release(metadata.len - offset, 0);
+					}
 					self.ctrl_connection.sendResponse(ServerResponse::FileStatusOk, "Ok to send data").await?;
-					self.save_data(file).await
+					self.save_data(file, path, offset, progress).await
 				} else {
+					if existing.is_none() {
+						self.quota_release(0, 1);
+					}
 					self.ctrl_connection.sendResponse(ServerResponse::PermissionDenied, "Cannot create file").await
 				};
 			}
+			return self.ctrl_connection.sendResponse(ServerResponse::PermissionDenied, "Cannot create file").await;
 		}
-		error!("Data connection not initialized");
-		Err(FtpError::DataConnectionError)
+		self.no_data_connection().await
 	}
 
 	/**
 	 * Same to STOR, but it save the data in one unique file. The data are sent through the control socket.
 	 */
 	async fn stou(&mut self, arg: PathBuf) -> FtpResult<()> {
+		if self.is_read_only() {
+			return self.ctrl_connection.sendResponse(ServerResponse::PermissionDenied, "Read-only account").await;
+		}
 		if self.data_connection.is_some() {
-			if let Some(mut path) = utils::get_absolut_path(&arg, self.current_work_directory.as_ref().unwrap()) {
-				let mut string_path = path.to_str().unwrap().to_string();
+			if let Some(mut path) = self.virtual_path(&arg) {
+				let base = path.clone().into_os_string();
 				let mut id = 1;
-				while path.exists() {
-					string_path.push('.');
-					string_path.push_str(id.to_string().as_str());
-					path = PathBuf::from(&string_path);
+				while self.metadata(path.as_path()).await.is_ok() {
+					let mut unique = base.clone();
+					unique.push(format!(".{}", id));
+					path = PathBuf::from(unique);
 					id += 1;
 				}
 
-				return if let Ok(file) = File::create(&path) {
-					let msg = format!("File: {}", &path.file_name().unwrap().to_str().unwrap());
+				if !self.quota_add_file() {
+					return self.ctrl_connection.sendResponse(ServerResponse::ExceededStorageAllocation, "File quota exceeded").await;
+				}
+				let target = path.clone();
+				return if let Ok(file) = self.with_storage(move |storage| storage.open_write(target.as_path(), 0)).await {
+					let msg = format!("File: {}", path.file_name().unwrap_or_default().to_string_lossy());
+					let progress = Progress::new(format!("STOU {}", path.display()), None);
 					self.ctrl_connection.sendResponse(ServerResponse::FileStatusOk, msg.as_str()).await?;
-					self.save_data(file).await
+					self.save_data(file, path, 0, progress).await
 				} else {
+					self.quota_release(0, 1);
 					self.ctrl_connection.sendResponse(ServerResponse::PermissionDenied, "Cannot create file").await
 				};
 			}
+			return self.ctrl_connection.sendResponse(ServerResponse::PermissionDenied, "Cannot create file").await;
 		}
-		error!("Data connection not initialized");
-		Err(FtpError::DataConnectionError)
+		self.no_data_connection().await
 	}
 
 	/**
@@ -678,67 +1413,333 @@ impl Client {
 	}
 
 	async fn transfer_type(&mut self, arg: TransferType) -> FtpResult<()> {
-		let message;
-		return match arg {
+		match arg {
 			TransferType::Unknown => {
 				self.ctrl_connection.sendResponse(ServerResponse::InvalidParameterOrArgument, "Transfert type unknown").await
 			}
 			_ => {
 				self.transfert_type = arg;
-				message = format!("Switch to {}", arg.to_string());
+				let message = format!("Switch to {}", arg);
 				self.ctrl_connection.sendResponse(ServerResponse::OK, message.as_str()).await
 			}
-		};
+		}
 	}
 
 	async fn unknown(&mut self, arg: String) -> FtpResult<()> {
 		self.ctrl_connection.sendResponse(ServerResponse::CommandNotImplemented, arg.as_str()).await
 	}
 
-	async fn save_data(&mut self, mut file: File) -> FtpResult<()> {
+	/**
+	 * Reply to a transfer command sent before PORT, PASV, EPRT or EPSV: the session goes on
+	 */
+	async fn no_data_connection(&mut self) -> FtpResult<()> {
+		error!("Data connection not initialized");
+		self.ctrl_connection.sendResponse(ServerResponse::BadSequenceOfCommands, "Use PORT, PASV, EPRT or EPSV first").await
+	}
+
+	/**
+	 * Take the data connection to start a transfer.
+	 * After PROT P, the TLS handshake is done here, once the client got the 150 reply.
+	 */
+	async fn open_data_connection(&mut self) -> FtpResult<Connection> {
+		let mut data_connection = self.data_connection.take().ok_or(FtpError::DataConnectionError)?;
+		if self.protected_data {
+			match self.context.tls_acceptor.as_ref() {
+				Some(acceptor) => data_connection.upgrade(acceptor).await?,
+				None => return Err(FtpError::TlsError),
+			}
+		}
+		Ok(data_connection)
+	}
+
+	/**
+	 * Write in the file all the bytes received through the data connection, until the client closes it.
+	 * In ASCII mode the end of lines are converted from CRLF to LF.
+	 * If the upload does not complete, the partial_uploads policy applies: initial_length is the length of the file
+	 * before the upload.
+	 * Each chunk is counted in the quota before it is written, the transfer stops with 552 when the quota is exceeded.
+	 */
+	async fn save_data(&mut self, mut file: WriteHandle, path: PathBuf, initial_length: u64, progress: Progress) -> FtpResult<()> {
 		debug!("Client::save_data");
 
-		let mut data_connection = self.data_connection.take().unwrap();
+		// The reservation made by ALLO is for this upload only, what is left is given back when it is dropped
+		let mut reservation = self.reservation.take();
+		let mut data_connection = match self.open_data_connection().await {
+			Ok(data_connection) => data_connection,
+			Err(e) => {
+				// Nothing was received: the file is put back as it was, whatever the partial_uploads policy
+				drop(file);
+				self.restore_upload_target(path, initial_length).await;
+				return self.transfer_failed(e).await;
+			}
+		};
+		let mut decoder = match self.transfert_type {
+			TransferType::Ascii => Some(AsciiDecoder::new()),
+			_ => None,
+		};
+		let quota = self.quota.clone();
+		let mut charge = move |bytes: usize| {
+			match quota.as_ref() {
+				Some(quota) if !quota.charge(bytes as u64, reservation.as_mut()) => Err(FtpError::QuotaExceeded),
+				_ => Ok(()),
+			}
+		};
+		let throttle = self.upload_throttle.clone();
+
+		let transfer = async {
+			let mut buffer = vec![0; TRANSFER_BUFFER_SIZE];
+			loop {
+				let n = data_connection.read_bytes(&mut buffer).await?;
+				if n == 0 {
+					break;
+				}
+				match decoder.as_mut() {
+					Some(decoder) => {
+						let data = decoder.decode(&buffer[..n]);
+						charge(data.len())?;
+						file.write_all(data.as_slice()).await?
+					}
+					None => {
+						charge(n)?;
+						file.write_all(&buffer[..n]).await?
+					}
+				}
+				progress.add(n);
+				throttle.consume(n).await;
+			}
+			if let Some(decoder) = decoder.as_mut() {
+				let data = decoder.finish();
+				charge(data.len())?;
+				file.write_all(data.as_slice()).await?;
+			}
+			file.flush().await?;
+			Ok::<_, FtpError>(())
+		};
+		let result = self.watch_transfer(transfer, &progress).await;
+		data_connection.close().await;
+
+		match result {
+			Ok(()) => self.ctrl_connection.sendResponse(ServerResponse::ClosingDataConnection, "Transfer complete").await,
+			Err(e) => {
+				// Wait for the write in progress before touching the file
+				let _ = file.flush().await;
+				drop(file);
+				self.discard_partial_upload(path, initial_length).await;
+				self.transfer_failed(e).await
+			}
+		}
+	}
 
-		if let Some(data) = data_connection.read().await {
-			writeln!(file, "{}", data)?;
-			data_connection.close().await;
-			self.data_connection = None;
-			return self.ctrl_connection.sendResponse(ServerResponse::ClosingDataConnection, "Transfer complete").await;
+	/**
+	 * Apply the partial_uploads policy to the file of an upload which did not complete.
+	 * What is discarded is given back to the quota.
+	 */
+	async fn discard_partial_upload(&self, path: PathBuf, initial_length: u64) {
+		if self.context.config.partial_uploads != PartialUploads::Keep {
+			self.restore_upload_target(path, initial_length).await;
 		}
-		error!("Cannot read data connection");
-		Err(FtpError::DataConnectionError)
 	}
 
-	async fn send_data(&mut self, data: Vec<String>) -> FtpResult<()> {
-		let mut data_connection = self.data_connection.take().unwrap();
+	/**
+	 * Put the file back as it was before the upload, initial_length bytes long or removed if it was created
+	 */
+	async fn restore_upload_target(&self, path: PathBuf, initial_length: u64) {
+		let target = path.clone();
+		let result = self.with_storage(move |storage| {
+			let length = storage.metadata(target.as_path())?.len;
+			if initial_length == 0 {
+				storage.remove_file(target.as_path()).map(|()| (length, 1))
+			} else {
+				storage.open_write(target.as_path(), initial_length).map(|_| (length.saturating_sub(initial_length), 0))
+			}
+		}).await;
+		match result {
+			Ok((bytes, files)) => {
+				self.quota_release(bytes, files);
+				info!("Partial upload {:?} discarded", path)
+			}
+			Err(e) => error!("Failed to discard partial upload {:?}: {}", path, e),
+		}
+	}
 
-		tokio::select! {
-			_ = async {
-				for msg in data {
-					data_connection.write(msg).await?;
+	/**
+	 * Stream a file through the data connection, chunk by chunk.
+	 * In ASCII mode the end of lines are converted from LF to CRLF.
+	 * Returns once the last byte is flushed and the data connection is closed.
+	 */
+	async fn send_file(&mut self, mut file: ReadHandle, progress: Progress) -> FtpResult<()> {
+		let mut data_connection = self.open_data_connection().await?;
+		let transfer_type = self.transfert_type;
+		let throttle = self.download_throttle.clone();
+
+		let transfer = async {
+			let mut buffer = vec![0; TRANSFER_BUFFER_SIZE];
+			loop {
+				let n = file.read(&mut buffer).await?;
+				if n == 0 {
+					break;
 				}
-				Ok::<_, FtpError>(())
-			} => {
-				data_connection.close().await;
-				self.data_connection = None;
-			}
-			cmd = self.ctrl_connection.read() => {
-				if cmd.is_some() {
-					match self.parse_command(&cmd.as_ref().unwrap()) {
-						ClientCommand::Abor => {
-							data_connection.close().await;
-							self.data_connection = None;
-							self.ctrl_connection.sendResponse(ServerResponse::ConnectionClosed, "transfer interrupted by ABORD").await?;
-							self.ctrl_connection.sendResponse(ServerResponse::ClosingDataConnection, "ABORD: ok").await?;
-							return Err(FtpError::Abord("End of transfer file".to_string()));
+				match transfer_type {
+					TransferType::Ascii => data_connection.write_bytes(ascii::to_network(&buffer[..n]).as_slice()).await?,
+					_ => data_connection.write_bytes(&buffer[..n]).await?,
+				}
+				progress.add(n);
+				throttle.consume(n).await;
+			}
+			data_connection.flush().await
+		};
+		let result = self.watch_transfer(transfer, &progress).await;
+		data_connection.close().await;
+		result
+	}
+
+	/**
+	 * The file to send with sendfile(2): only in Image mode without TLS, and if the storage keeps its files on the
+	 * local file system. Otherwise the file is streamed by send_file.
+	 */
+	async fn zero_copy_file(&self, path: &Path) -> Option<std::fs::File> {
+		if !self.context.config.zero_copy || !cfg!(target_os = "linux")
+			|| self.transfert_type != TransferType::Binary || self.protected_data {
+			return None;
+		}
+		let path = path.to_path_buf();
+		self.with_storage(move |storage| storage.local_file(path.as_path())).await.ok().flatten()
+	}
+
+	/**
+	 * Send a local file from offset to length with sendfile(2): the data don't go through the server.
+	 * With a bandwidth limit, the chunks are as small as those of send_file so the rate stays smooth.
+	 */
+	async fn send_local_file(&mut self, file: std::fs::File, offset: u64, length: u64, progress: Progress) -> FtpResult<()> {
+		let mut data_connection = self.open_data_connection().await?;
+		let throttle = self.download_throttle.clone();
+		let chunk_size = match throttle.limit() {
+			Some(_) => TRANSFER_BUFFER_SIZE,
+			None => ZERO_COPY_CHUNK_SIZE,
+		};
+
+		let transfer = async {
+			let mut position = offset;
+			while position < length {
+				let count = (length - position).min(chunk_size as u64) as usize;
+				let n = data_connection.send_file(&file, position, count).await?;
+				if n == 0 {
+					// The file was cut during the download: the client must not take what it got for the whole file
+					error!("File cut during the download, {} bytes sent of {}", position - offset, length - offset);
+					return Err(FtpError::FileSystemError);
+				}
+				position += n as u64;
+				progress.add(n);
+				throttle.consume(n).await;
+			}
+			Ok(())
+		};
+		let result = self.watch_transfer(transfer, &progress).await;
+		data_connection.close().await;
+		result
+	}
+
+	async fn send_listing(&mut self, data: Vec<Vec<u8>>, command: String) -> FtpResult<()> {
+		self.ctrl_connection.sendResponse(ServerResponse::FileStatusOk, "Here comes the directory listing").await?;
+		match self.send_data(data, command).await {
+			Ok(()) => self.ctrl_connection.sendResponse(ServerResponse::ClosingDataConnection, "Directory send OK").await,
+			Err(e) => self.transfer_failed(e).await,
+		}
+	}
+
+	/**
+	 * Send lines through the data connection, used by the listings
+	 */
+	async fn send_data(&mut self, data: Vec<Vec<u8>>, command: String) -> FtpResult<()> {
+		let mut data_connection = self.open_data_connection().await?;
+		let size = data.iter().map(|msg| msg.len() as u64 + 2).sum();
+		let progress = Progress::new(command, Some(size));
+		let throttle = self.download_throttle.clone();
+
+		let transfer = async {
+			for msg in data {
+				data_connection.write_bytes([msg.as_slice(), b"\r\n"].concat().as_slice()).await?;
+				progress.add(msg.len() + 2);
+				throttle.consume(msg.len() + 2).await;
+			}
+			data_connection.flush().await
+		};
+		let result = self.watch_transfer(transfer, &progress).await;
+		data_connection.close().await;
+		result
+	}
+
+	/**
+	 * Run a transfer and listen to the control connection at the same time:
+	 * ABOR stops the transfer with an Abord error, STAT reports its progress, other commands run after the transfer.
+	 * The idle timeout of the control connection does not apply: the client is waiting for the transfer.
+	 */
+	async fn watch_transfer<F>(&mut self, transfer: F, progress: &Progress) -> FtpResult<()>
+	where F: Future<Output = FtpResult<()>> {
+		tokio::pin!(transfer);
+
+		loop {
+			tokio::select! {
+				result = &mut transfer => {
+					return result;
+				}
+				cmd = self.ctrl_connection.read_untimed() => {
+					match cmd {
+						Some(cmd) => {
+							match self.parse_command(&cmd) {
+								ClientCommand::Abor => {
+									return Err(FtpError::Abord(progress.command().to_string()));
+								}
+								ClientCommand::Stat(None) => {
+									self.ctrl_connection.send_reply(progress.reply()).await?;
+								}
+								_ if self.deferred_commands.len() < MAX_DEFERRED_COMMANDS => {
+									self.deferred_commands.push_back(cmd);
+								}
+								_ => {
+									self.ctrl_connection.sendResponse(ServerResponse::BadSequenceOfCommands, "Transfer in progress").await?;
+								}
+							}
+						}
+						None => {
+							// Client gone: nobody can abort the transfer, let it finish on its own.
+							return transfer.await;
 						}
-						_ => { }
 					}
 				}
 			}
 		}
-		Ok(())
+	}
+
+	/**
+	 * Replies to a transfer which did not complete. After ABOR, 426 is for the transfer and 226 for ABOR itself.
+	 */
+	async fn transfer_failed(&mut self, error: FtpError) -> FtpResult<()> {
+		match error {
+			FtpError::Abord(command) => {
+				info!("Transfer aborted: {}", command);
+				self.ctrl_connection.sendResponse(ServerResponse::ConnectionClosed, "Transfer aborted by ABOR").await?;
+				self.ctrl_connection.sendResponse(ServerResponse::ClosingDataConnection, "ABOR successful").await
+			}
+			FtpError::QuotaExceeded => {
+				info!("Transfer stopped, quota exceeded");
+				self.ctrl_connection.sendResponse(ServerResponse::ExceededStorageAllocation, "Quota exceeded, transfer aborted").await
+			}
+			FtpError::StorageFull => {
+				error!("Transfer stopped, no space left on device");
+				self.ctrl_connection.sendResponse(ServerResponse::InsufficientStorageSpace, "Transfer aborted").await
+			}
+			FtpError::TlsError => {
+				self.ctrl_connection.sendResponse(ServerResponse::CantOpenDataConnection, "TLS negotiation of the data connection failed").await
+			}
+			FtpError::FileSystemError => {
+				self.ctrl_connection.sendResponse(ServerResponse::LocalErrorInProcessing, "File changed during the transfer, transfer aborted").await
+			}
+			e => {
+				error!("Transfer failed: {}", e);
+				self.ctrl_connection.sendResponse(ServerResponse::ConnectionClosed, "Transfer aborted").await
+			}
+		}
 	}
 
 	pub async fn close_connection(&mut self) {
@@ -751,4 +1752,5 @@ impl Client {
 		}
 		self.ctrl_connection.close().await;
 	}
-}
\ No newline at end of file
+}
+
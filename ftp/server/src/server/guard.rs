@@ -0,0 +1,207 @@
+/* Copyright 2022 Pierrick MARIE
+
+This file is part of rust-discovery
+
+LCS is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+Rust-discovery is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with rust-discovery.  If not, see <http://www.gnu.org/licenses/>. */
+
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use log::info;
+
+use crate::config::Config;
+
+const MAX_LOGIN_DELAY: Duration = Duration::from_secs(30);
+
+/**
+ * Failed logins of an address or a user name, forgotten ban_duration after the last one
+ */
+#[derive(Debug, Clone, Copy)]
+struct Failures {
+	count: u32,
+	last: Instant,
+}
+
+/**
+ * What to do after a failed PASS
+ */
+#[derive(Debug, Clone, Copy)]
+pub struct Failure {
+	pub delay: Duration, // Before the reply
+	pub banned: bool, // The address has just been banned: the session ends
+}
+
+/**
+ * Protection against password guessing.
+ * Each failed PASS delays the reply, longer and longer for the address and the user name.
+ * After max_login_failures failures, the address is banned for ban_duration: its connections are refused.
+ */
+pub struct LoginGuard {
+	delay: Duration,
+	max_failures: u32, // 0: addresses are never banned
+	ban_duration: Duration,
+	addresses: Mutex<HashMap<IpAddr, Failures>>,
+	users: Mutex<HashMap<String, Failures>>,
+	bans: Mutex<HashMap<IpAddr, Instant>>, // End of the ban of each address
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+	mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+impl LoginGuard {
+	pub fn new(config: &Config) -> Self {
+		LoginGuard::with_limits(Duration::from_secs(config.login_failure_delay), config.max_login_failures,
+			Duration::from_secs(config.ban_duration))
+	}
+
+	fn with_limits(delay: Duration, max_failures: u32, ban_duration: Duration) -> Self {
+		LoginGuard {
+			delay,
+			max_failures,
+			ban_duration,
+			addresses: Mutex::new(HashMap::new()),
+			users: Mutex::new(HashMap::new()),
+			bans: Mutex::new(HashMap::new()),
+		}
+	}
+
+	/**
+	 * Time left before the ban of an address ends, None if it is not banned
+	 */
+	pub fn banned(&self, ip: IpAddr) -> Option<Duration> {
+		let mut bans = lock(&self.bans);
+		let now = Instant::now();
+		bans.retain(|_, end| *end > now);
+		bans.get(&ip).map(|end| *end - now)
+	}
+
+	/**
+	 * Count a failed PASS of user from ip
+	 */
+	pub fn failure(&self, ip: IpAddr, user: &str) -> Failure {
+		let ip_failures = self.count(&self.addresses, ip);
+		let user_failures = self.count(&self.users, user.to_string());
+
+		let banned = self.max_failures > 0 && ip_failures >= self.max_failures;
+		if banned {
+			info!("Address {} banned for {:?} after {} failed logins", ip, self.ban_duration, ip_failures);
+			lock(&self.bans).insert(ip, Instant::now() + self.ban_duration);
+			lock(&self.addresses).remove(&ip);
+		}
+		// The delay doubles with each failure
+		let failures = ip_failures.max(user_failures);
+		let delay = self.delay.saturating_mul(2u32.saturating_pow(failures - 1)).min(MAX_LOGIN_DELAY);
+		Failure { delay, banned }
+	}
+
+	/**
+	 * Forget the failures of an address and a user name after a successful login
+	 */
+	pub fn success(&self, ip: IpAddr, user: &str) {
+		lock(&self.addresses).remove(&ip);
+		lock(&self.users).remove(user);
+	}
+
+	/**
+	 * Add a failure, returns the number of failures not forgotten yet
+	 */
+	fn count<K: Eq + Hash>(&self, failures: &Mutex<HashMap<K, Failures>>, key: K) -> u32 {
+		let mut failures = lock(failures);
+		let now = Instant::now();
+		failures.retain(|_, failures| now.duration_since(failures.last) < self.ban_duration);
+		let entry = failures.entry(key).or_insert(Failures { count: 0, last: now });
+		entry.count += 1;
+		entry.last = now;
+		entry.count
+	}
+
+	/**
+	 * Banned addresses and the time left before their ban ends
+	 */
+	pub fn bans(&self) -> Vec<(IpAddr, Duration)> {
+		let mut bans = lock(&self.bans);
+		let now = Instant::now();
+		bans.retain(|_, end| *end > now);
+		let mut list: Vec<_> = bans.iter().map(|(ip, end)| (*ip, *end - now)).collect();
+		list.sort();
+		list
+	}
+
+	/**
+	 * Lift the ban of an address, false if it was not banned
+	 */
+	pub fn unban(&self, ip: IpAddr) -> bool {
+		lock(&self.addresses).remove(&ip);
+		lock(&self.bans).remove(&ip).is_some()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::thread::sleep;
+
+	const CLIENT: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(192, 0, 2, 1));
+	const OTHER: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(192, 0, 2, 2));
+
+	#[test]
+	fn delay_doubles_up_to_the_maximum() {
+		let guard = LoginGuard::with_limits(Duration::from_secs(1), 0, Duration::from_secs(900));
+		let delays: Vec<_> = (0..7).map(|_| guard.failure(CLIENT, "alice").delay.as_secs()).collect();
+		assert_eq!(delays, vec![1, 2, 4, 8, 16, 30, 30]);
+		assert!(guard.banned(CLIENT).is_none());
+	}
+
+	#[test]
+	fn user_failures_count_from_every_address() {
+		let guard = LoginGuard::with_limits(Duration::from_secs(1), 0, Duration::from_secs(900));
+		guard.failure(CLIENT, "alice");
+		assert_eq!(guard.failure(OTHER, "alice").delay, Duration::from_secs(2));
+		assert_eq!(guard.failure(OTHER, "bob").delay, Duration::from_secs(2));
+		guard.success(OTHER, "alice");
+		assert_eq!(guard.failure(OTHER, "carol").delay, Duration::from_secs(1));
+	}
+
+	#[test]
+	fn ban_after_max_failures_and_unban() {
+		let guard = LoginGuard::with_limits(Duration::ZERO, 3, Duration::from_secs(900));
+		assert!(!guard.failure(CLIENT, "alice").banned);
+		assert!(!guard.failure(CLIENT, "bob").banned);
+		assert!(guard.failure(CLIENT, "carol").banned);
+		assert!(guard.banned(CLIENT).is_some_and(|left| left > Duration::from_secs(800)));
+		assert!(guard.banned(OTHER).is_none());
+		assert_eq!(guard.bans().iter().map(|(ip, _)| *ip).collect::<Vec<_>>(), vec![CLIENT]);
+		assert!(guard.unban(CLIENT));
+		assert!(!guard.unban(CLIENT));
+		assert!(guard.banned(CLIENT).is_none());
+		assert!(!guard.failure(CLIENT, "alice").banned);
+	}
+
+	#[test]
+	fn bans_and_failures_expire() {
+		let guard = LoginGuard::with_limits(Duration::ZERO, 2, Duration::from_millis(50));
+		guard.failure(CLIENT, "alice");
+		assert!(guard.failure(CLIENT, "alice").banned);
+		sleep(Duration::from_millis(60));
+		assert!(guard.banned(CLIENT).is_none());
+		assert!(guard.bans().is_empty());
+		guard.failure(OTHER, "bob");
+		sleep(Duration::from_millis(60));
+		assert!(!guard.failure(OTHER, "bob").banned);
+	}
+}
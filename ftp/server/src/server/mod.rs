@@ -23,33 +23,305 @@ I modified some parts of it.
 
 use log::{debug, error, info};
 use tokio::net::{TcpListener, TcpStream};
-use crate::{ADDR, Client, PORT};
+use crate::Client;
+use crate::config::{Config, StorageBackend};
+use crate::config::access;
+use crate::protocol::ServerResponse;
 use crate::utils::connection::Connection;
+use crate::utils::tls;
 use async_shutdown::Shutdown;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use futures::future::select_all;
+use socket2::SockRef;
+use tokio_rustls::TlsAcceptor;
+use crate::server::account::{Account, Accounts};
+use crate::server::auth::{Authenticator, PasswordFile};
+use crate::server::guard::LoginGuard;
+use crate::server::quota::Quota;
+use crate::server::throttle::{Bandwidth, Direction, Throttle};
+use crate::storage::Storage;
+use crate::storage::local::LocalStorage;
+use crate::storage::memory::MemoryStorage;
 
+pub mod account;
+pub mod auth;
 pub mod client;
+pub mod guard;
+pub mod quota;
+pub mod session;
+pub mod throttle;
+pub mod transfer;
 
+/**
+ * Everything shared by the sessions: the configuration and what is loaded from it at startup.
+ */
+pub struct ServerContext {
+	pub config: Config,
+	pub accounts: Arc<Accounts>,
+	pub authenticator: Arc<dyn Authenticator>,
+	pub tls_acceptor: Option<TlsAcceptor>,
+	pub login_guard: LoginGuard, // Failed logins and banned addresses
+	clients: AtomicUsize, // Number of running sessions
+	clients_per_ip: Mutex<HashMap<IpAddr, usize>>, // Running sessions of each client address
+	logins: Mutex<HashMap<String, usize>>, // Sessions logged in as each user
+	memory_storages: Mutex<HashMap<String, Arc<dyn Storage>>>, // With the memory backend, the files of each user
+	quotas: Mutex<HashMap<String, Arc<Quota>>>, // Usage of the users with a quota, kept across their sessions
+	bandwidth: Bandwidth, // Limits of the whole server
+	user_bandwidths: Mutex<HashMap<String, Arc<Bandwidth>>>, // Limits shared by the sessions of each user
+}
+
+impl ServerContext {
+	pub fn new(config: Config) -> std::io::Result<Self> {
+		// Load the virtual users.
+		let accounts = Arc::new(Accounts::load(config.users_file.as_path())?);
+
+		// Load the credentials checked after each PASS command.
+		// A password file, if any, takes precedence over the hashes of the users file.
+		let authenticator: Arc<dyn Authenticator> = match config.password_file.as_ref() {
+			Some(path) => Arc::new(PasswordFile::load(path.as_path())?),
+			None => accounts.clone(),
+		};
+
+		// Explicit FTPS (AUTH TLS) is available if a certificate is configured.
+		let tls_acceptor = match (config.tls_certificate.as_ref(), config.tls_private_key.as_ref()) {
+			(Some(certificate), Some(private_key)) => Some(tls::load_acceptor(certificate.as_path(), private_key.as_path())?),
+			_ => None,
+		};
+
+		let bandwidth = Bandwidth::new(config.server_download_limit, config.server_upload_limit);
+		let login_guard = LoginGuard::new(&config);
+
+		Ok(ServerContext {
+			config,
+			accounts,
+			authenticator,
+			tls_acceptor,
+			login_guard,
+			clients: AtomicUsize::new(0),
+			clients_per_ip: Mutex::new(HashMap::new()),
+			logins: Mutex::new(HashMap::new()),
+			memory_storages: Mutex::new(HashMap::new()),
+			quotas: Mutex::new(HashMap::new()),
+			bandwidth,
+			user_bandwidths: Mutex::new(HashMap::new()),
+		})
+	}
+
+	/**
+	 * Storage of the files of a user who just logged in.
+	 * With the memory backend, all the sessions of a user share the same files.
+	 */
+	pub fn storage(&self, account: &Account) -> std::io::Result<Arc<dyn Storage>> {
+		match self.config.storage {
+			StorageBackend::Local => Ok(Arc::new(LocalStorage::new(account.root.as_path())?)),
+			StorageBackend::Memory => {
+				let mut storages = self.memory_storages.lock().unwrap_or_else(|e| e.into_inner());
+				Ok(storages.entry(account.name.clone()).or_insert_with(|| Arc::new(MemoryStorage::new())).clone())
+			}
+		}
+	}
+
+	/**
+	 * Quota of a user who just logged in, None if the account has no limit.
+	 * The usage is counted from the storage at the first login only: it reads the whole tree of the user.
+	 */
+	pub fn quota(&self, account: &Account, storage: &dyn Storage) -> Option<Arc<Quota>> {
+		if account.quota_bytes.is_none() && account.quota_files.is_none() {
+			return None;
+		}
+		if let Some(quota) = self.quotas.lock().unwrap_or_else(|e| e.into_inner()).get(&account.name) {
+			return Some(quota.clone());
+		}
+		let usage = Quota::count(storage);
+		let mut quotas = self.quotas.lock().unwrap_or_else(|e| e.into_inner());
+		let quota = quotas.entry(account.name.clone())
+			.or_insert_with(|| Arc::new(Quota::new(account.quota_bytes, account.quota_files, usage)));
+		Some(quota.clone())
+	}
 
-pub async fn run(shutdown: Shutdown) -> std::io::Result<()> {
+	/**
+	 * Download and upload throttles of a new session of a user: its own limits, those of the user and of the server
+	 */
+	pub fn throttles(&self, account: &Account) -> (Throttle, Throttle) {
+		let config = &self.config;
+		let session = Bandwidth::new(config.session_download_limit, config.session_upload_limit);
+		let user = self.user_bandwidths.lock().unwrap_or_else(|e| e.into_inner())
+			.entry(account.name.clone())
+			.or_insert_with(|| Arc::new(Bandwidth::new(config.user_download_limit, config.user_upload_limit)))
+			.clone();
+		let levels = [&session, user.as_ref(), &self.bandwidth];
+		(Throttle::new(&levels, Direction::Download), Throttle::new(&levels, Direction::Upload))
+	}
+
+	/**
+	 * Count a new login of a user, None if the user already has as many sessions as allowed
+	 */
+	pub fn login(self: &Arc<Self>, account: &Account) -> Option<LoginSlot> {
+		let max = account.max_logins.or(self.config.max_logins_per_user);
+		let mut logins = self.logins.lock().unwrap_or_else(|e| e.into_inner());
+		let count = logins.entry(account.name.clone()).or_insert(0);
+		if max.is_some_and(|max| *count >= max) {
+			return None;
+		}
+		*count += 1;
+		Some(LoginSlot {
+			context: self.clone(),
+			name: account.name.clone(),
+		})
+	}
+
+	pub fn idle_timeout(&self) -> Duration {
+		Duration::from_secs(self.config.idle_timeout)
+	}
+
+	pub fn data_timeout(&self) -> Duration {
+		Duration::from_secs(self.config.data_timeout)
+	}
+}
+
+/**
+ * A running session, counted until it is dropped.
+ */
+struct ClientSlot {
+	context: Arc<ServerContext>,
+	ip: IpAddr,
+}
 
-	let server = TcpListener::bind(format!("{}:{}", ADDR, PORT)).await?;
-	info!("Server listening on {:?}", server.local_addr().unwrap());
+impl ClientSlot {
+	/**
+	 * Count a new session, None if the server or the address of the client already has too many
+	 */
+	fn new(context: Arc<ServerContext>, address: SocketAddr) -> Option<Self> {
+		// An IPv4 client of an IPv6 socket has the same address as on an IPv4 socket
+		let ip = address.ip().to_canonical();
+		if context.clients.load(Ordering::SeqCst) >= context.config.max_clients {
+			return None;
+		}
+		let mut clients_per_ip = context.clients_per_ip.lock().unwrap_or_else(|e| e.into_inner());
+		let count = clients_per_ip.get(&ip).copied().unwrap_or(0);
+		if context.config.max_clients_per_ip.is_some_and(|max| count >= max) {
+			return None;
+		}
+		clients_per_ip.insert(ip, count + 1);
+		drop(clients_per_ip);
+		context.clients.fetch_add(1, Ordering::SeqCst);
+		Some(ClientSlot { context, ip })
+	}
+}
+
+impl Drop for ClientSlot {
+	fn drop(&mut self) {
+		self.context.clients.fetch_sub(1, Ordering::SeqCst);
+		let mut clients_per_ip = self.context.clients_per_ip.lock().unwrap_or_else(|e| e.into_inner());
+		if let Some(count) = clients_per_ip.get_mut(&self.ip) {
+			*count -= 1;
+			if *count == 0 {
+				clients_per_ip.remove(&self.ip);
+			}
+		}
+	}
+}
+
+/**
+ * A logged user, counted until it is dropped: logout, REIN or end of the session.
+ */
+pub struct LoginSlot {
+	context: Arc<ServerContext>,
+	name: String,
+}
+
+impl Drop for LoginSlot {
+	fn drop(&mut self) {
+		let mut logins = self.context.logins.lock().unwrap_or_else(|e| e.into_inner());
+		if let Some(count) = logins.get_mut(&self.name) {
+			*count -= 1;
+			if *count == 0 {
+				logins.remove(&self.name);
+			}
+		}
+	}
+}
+
+pub async fn run(shutdown: Shutdown, context: ServerContext) -> std::io::Result<()> {
+
+	let context = Arc::new(context);
+	let listeners = bind(&context.config.listen_addresses, context.config.port).await?;
 	let mut id = 1;
 
 	// Simply use `wrap_cancel` for everything, since we do not need clean-up for the listening socket.
 	// See `handle_client` for a case where a future is given the time to perform logging after the shutdown was triggered.
-	while let Some(connection) = shutdown.wrap_cancel(server.accept()).await {
+	while let Some(connection) = shutdown.wrap_cancel(accept(&listeners)).await {
 		let (stream, address) = connection?;
+		// Denied, banned or over the limits, the client gets a reply without starting a session
+		if !access::is_allowed(&context.config.client_rules, address.ip()) {
+			info!("Address denied by client_rules, connection refused: {}", address);
+			tokio::spawn(refuse_client(stream, context.idle_timeout(), "Access denied"));
+			continue;
+		}
+		if context.login_guard.banned(address.ip().to_canonical()).is_some() {
+			info!("Banned address, connection refused: {}", address);
+			tokio::spawn(refuse_client(stream, context.idle_timeout(), "Too many failed logins, try again later"));
+			continue;
+		}
+		let slot = match ClientSlot::new(context.clone(), address) {
+			Some(slot) => slot,
+			None => {
+				info!("Too many connections, connection refused: {}", address);
+				tokio::spawn(refuse_client(stream, context.idle_timeout(), "Too many connections"));
+				continue;
+			}
+		};
 		// Handle a new client
-		tokio::spawn(handle_client(shutdown.clone(), stream, address, id));
+		tokio::spawn(handle_client(shutdown.clone(), stream, address, id, slot));
 		id += 1;
 	}
 
 	Ok(())
 }
 
-async fn handle_client(shutdown: Shutdown, stream: TcpStream, address: SocketAddr, id: i32) {
+/**
+ * Listen on every address (IPv4 and IPv6) that can be bound. Fails only if none of them can be.
+ */
+async fn bind(addresses: &[IpAddr], port: u16) -> std::io::Result<Vec<TcpListener>> {
+	let mut listeners = vec![];
+	for address in addresses {
+		match TcpListener::bind(SocketAddr::new(*address, port)).await {
+			Ok(listener) => {
+				info!("Server listening on {:?}", listener.local_addr()?);
+				listeners.push(listener);
+			}
+			Err(e) => error!("Failed to listen on {}: {}", address, e),
+		}
+	}
+	if listeners.is_empty() {
+		return Err(std::io::Error::new(std::io::ErrorKind::AddrNotAvailable, "No listen address available"));
+	}
+	Ok(listeners)
+}
+
+/**
+ * Wait for a new client on any of the listeners.
+ */
+async fn accept(listeners: &[TcpListener]) -> std::io::Result<(TcpStream, SocketAddr)> {
+	let (connection, _, _) = select_all(listeners.iter().map(|listener| Box::pin(listener.accept()))).await;
+	connection
+}
+
+/**
+ * Tell a client why its connection is refused, without starting a session.
+ */
+async fn refuse_client(stream: TcpStream, timeout: Duration, message: &str) {
+	let mut connection = Connection::new(stream, timeout);
+	if connection.sendResponse(ServerResponse::ServiceNotAvailable, message).await.is_ok() {
+		connection.close().await;
+	}
+}
+
+async fn handle_client(shutdown: Shutdown, stream: TcpStream, address: SocketAddr, id: i32, slot: ClientSlot) {
 	info!("Accepted new connection from {}", address);
 
 	// Make sure the shutdown doesn't complete until the delay token is dropped.
@@ -71,9 +343,13 @@ async fn handle_client(shutdown: Shutdown, stream: TcpStream, address: SocketAdd
 		}
 	};
 
-	let (rx, tx) = stream.into_split();
-	let connection = Connection::new(rx, tx);
-	let mut client = Client::new(connection, id);
+	// ABOR may be sent as urgent data: keep it in the stream so the control connection reads the whole command.
+	if let Err(e) = SockRef::from(&stream).set_out_of_band_inline(true) {
+		error!("Failed to keep urgent data inline for {}: {:?}", address, e);
+	}
+
+	let connection = Connection::new(stream, slot.context.idle_timeout());
+	let mut client = Client::new(connection, id, slot.context.clone());
 
 	// Now run the echo loop, but cancel it when the shutdown is triggered.
 	match shutdown.wrap_cancel(client.run()).await {
@@ -85,5 +361,5 @@ async fn handle_client(shutdown: Shutdown, stream: TcpStream, address: SocketAdd
 		}
 	}
 
-	// The delay token will be dropped here, allowing the shutdown to complete.
+	// The delay token and the client slot will be dropped here, allowing the shutdown to complete.
 }
@@ -0,0 +1,299 @@
+/* Copyright 2022 Pierrick MARIE
+
+This file is part of rust-discovery
+
+LCS is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+Rust-discovery is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with rust-discovery.  If not, see <http://www.gnu.org/licenses/>. */
+
+use std::collections::HashSet;
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use log::debug;
+
+use crate::protocol::reply::Reply;
+use crate::protocol::ServerResponse;
+use crate::storage::Storage;
+
+/**
+ * What a user stores, and the bytes booked by ALLO for uploads to come
+ */
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Usage {
+	pub bytes: u64,
+	pub files: u64, // Regular files only, directories are not counted
+	pub reserved: u64,
+}
+
+/**
+ * The limits of a user and their usage, shared by all the sessions of the user.
+ * The usage is counted from the storage at the first login, then each command updates it.
+ */
+pub struct Quota {
+	max_bytes: Option<u64>,
+	max_files: Option<u64>,
+	usage: Mutex<Usage>,
+}
+
+impl Quota {
+	pub fn new(max_bytes: Option<u64>, max_files: Option<u64>, usage: Usage) -> Self {
+		Quota {
+			max_bytes,
+			max_files,
+			usage: Mutex::new(usage),
+		}
+	}
+
+	/**
+	 * Count the files stored by a user, in every directory. It reads the whole tree: call it on the blocking pool.
+	 * The directories which cannot be read, such as links outside the root directory, are skipped.
+	 * A file or a directory reached again through a link is counted once: a link to a parent directory ends the walk.
+	 */
+	pub fn count(storage: &dyn Storage) -> Usage {
+		debug!("Quota::count");
+		let mut usage = Usage::default();
+		let mut seen = HashSet::new(); // Device and inode of the files and directories already counted
+		if let Ok(root) = storage.metadata(Path::new("/")) {
+			seen.insert((root.device, root.inode));
+		}
+		let mut directories = vec![Path::new("/").to_path_buf()];
+		while let Some(directory) = directories.pop() {
+			let entries = match storage.list(directory.as_path()) {
+				Ok(entries) => entries,
+				Err(e) => {
+					debug!("Quota::count skips {:?}: {}", directory, e);
+					continue;
+				}
+			};
+			for entry in entries {
+				if !seen.insert((entry.metadata.device, entry.metadata.inode)) {
+					continue;
+				}
+				if entry.metadata.is_dir {
+					directories.push(directory.join(entry.name));
+				} else if entry.metadata.is_file {
+					usage.bytes += entry.metadata.len;
+					usage.files += 1;
+				}
+			}
+		}
+		usage
+	}
+
+	fn lock(&self) -> MutexGuard<'_, Usage> {
+		self.usage.lock().unwrap_or_else(|e| e.into_inner())
+	}
+
+	/**
+	 * Bytes which can still be written without the reserved ones
+	 */
+	fn available(&self, usage: &Usage) -> u64 {
+		self.max_bytes.map_or(u64::MAX, |max| max.saturating_sub(usage.bytes + usage.reserved))
+	}
+
+	/**
+	 * Book bytes for the next upload of the session (ALLO). None if the quota is too small.
+	 */
+	pub fn reserve(self: &Arc<Self>, bytes: u64) -> Option<Reservation> {
+		let mut usage = self.lock();
+		if bytes > self.available(&usage) {
+			return None;
+		}
+		usage.reserved += bytes;
+		Some(Reservation { quota: self.clone(), bytes })
+	}
+
+	/**
+	 * Count a new file, false if the user already has as many files as allowed
+	 */
+	pub fn add_file(&self) -> bool {
+		let mut usage = self.lock();
+		if self.max_files.is_some_and(|max| usage.files >= max) {
+			return false;
+		}
+		usage.files += 1;
+		true
+	}
+
+	/**
+	 * Count bytes about to be written, false if they don't fit in the quota.
+	 * The bytes booked by the reservation are used first.
+	 */
+	pub fn charge(&self, bytes: u64, reservation: Option<&mut Reservation>) -> bool {
+		let mut usage = self.lock();
+		let booked = reservation.as_ref().map_or(0, |reservation| reservation.bytes.min(bytes));
+		if bytes - booked > self.available(&usage) {
+			return false;
+		}
+		if let Some(reservation) = reservation {
+			reservation.bytes -= booked;
+		}
+		usage.reserved -= booked;
+		usage.bytes += bytes;
+		true
+	}
+
+	/**
+	 * Forget removed files, or bytes cut from a file
+	 */
+	pub fn release(&self, bytes: u64, files: u64) {
+		let mut usage = self.lock();
+		usage.bytes = usage.bytes.saturating_sub(bytes);
+		usage.files = usage.files.saturating_sub(files);
+	}
+
+	/**
+	 * Reply to SITE QUOTA
+	 */
+	pub fn reply(&self, user: &str) -> Reply {
+		let usage = *self.lock();
+		let limit = |max: Option<u64>| max.map_or("unlimited".to_string(), |max| max.to_string());
+		Reply::new(ServerResponse::OK)
+			.line(format!("Quota of {}", user))
+			.line(format!("Bytes: {} used of {}", usage.bytes, limit(self.max_bytes)))
+			.line(format!("Files: {} used of {}", usage.files, limit(self.max_files)))
+			.line(format!("Reserved by ALLO: {} bytes", usage.reserved))
+			.line("End of quota")
+	}
+}
+
+/**
+ * Bytes booked by ALLO and not written yet. They are given back to the quota when it is dropped.
+ */
+pub struct Reservation {
+	quota: Arc<Quota>,
+	bytes: u64,
+}
+
+impl Reservation {
+	/**
+	 * Book bytes instead of the current ones (a new ALLO). False if they don't fit, the reservation is kept then.
+	 */
+	pub fn resize(&mut self, bytes: u64) -> bool {
+		let mut usage = self.quota.lock();
+		if bytes > self.quota.available(&usage) + self.bytes {
+			return false;
+		}
+		usage.reserved = usage.reserved - self.bytes + bytes;
+		self.bytes = bytes;
+		true
+	}
+}
+
+impl Drop for Reservation {
+	fn drop(&mut self) {
+		let mut usage = self.quota.lock();
+		usage.reserved -= self.bytes;
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::fs;
+	use std::os::unix::fs::symlink;
+	use crate::storage::local::LocalStorage;
+
+	fn tree() -> tempfile::TempDir {
+		let root = tempfile::tempdir().unwrap();
+		fs::write(root.path().join("a.txt"), b"12345").unwrap();
+		fs::create_dir(root.path().join("sub")).unwrap();
+		fs::write(root.path().join("sub/b.txt"), b"123").unwrap();
+		root
+	}
+
+	#[test]
+	fn count_files_of_every_directory() {
+		let root = tree();
+		let usage = Quota::count(&LocalStorage::new(root.path()).unwrap());
+		assert_eq!((usage.bytes, usage.files), (8, 2));
+	}
+
+	#[test]
+	fn count_stops_at_a_link_to_a_parent_directory() {
+		let root = tree();
+		symlink(".", root.path().join("loop")).unwrap();
+		symlink("..", root.path().join("sub/up")).unwrap();
+		let usage = Quota::count(&LocalStorage::new(root.path()).unwrap());
+		assert_eq!((usage.bytes, usage.files), (8, 2));
+	}
+
+	#[test]
+	fn count_linked_directories_and_files_once() {
+		let root = tree();
+		symlink("sub", root.path().join("sub2")).unwrap();
+		symlink("a.txt", root.path().join("a2.txt")).unwrap();
+		let usage = Quota::count(&LocalStorage::new(root.path()).unwrap());
+		assert_eq!((usage.bytes, usage.files), (8, 2));
+	}
+
+	#[test]
+	fn reserve_books_bytes_until_dropped() {
+		let quota = Arc::new(Quota::new(Some(100), None, Usage { bytes: 40, ..Usage::default() }));
+		let reservation = quota.reserve(50).unwrap();
+		assert_eq!(quota.lock().reserved, 50);
+		assert!(quota.reserve(11).is_none());
+		drop(reservation);
+		assert_eq!(quota.lock().reserved, 0);
+		assert!(quota.reserve(60).is_some());
+	}
+
+	#[test]
+	fn resize_keeps_the_reservation_when_too_large() {
+		let quota = Arc::new(Quota::new(Some(100), None, Usage::default()));
+		let mut reservation = quota.reserve(80).unwrap();
+		assert!(reservation.resize(90));
+		assert_eq!(quota.lock().reserved, 90);
+		assert!(!reservation.resize(101));
+		assert_eq!(reservation.bytes, 90);
+		assert_eq!(quota.lock().reserved, 90);
+		assert!(reservation.resize(10));
+		assert_eq!(quota.lock().reserved, 10);
+	}
+
+	#[test]
+	fn charge_uses_the_reservation_first() {
+		let quota = Arc::new(Quota::new(Some(100), None, Usage::default()));
+		let mut reservation = quota.reserve(80).unwrap();
+		// Bytes beyond the reservation must fit in what is left
+		assert!(!quota.charge(101, Some(&mut reservation)));
+		assert!(quota.charge(50, Some(&mut reservation)));
+		assert_eq!(reservation.bytes, 30);
+		assert!(quota.charge(50, Some(&mut reservation)));
+		assert_eq!(reservation.bytes, 0);
+		let usage = *quota.lock();
+		assert_eq!((usage.bytes, usage.reserved), (100, 0));
+		assert!(!quota.charge(1, None));
+		drop(reservation);
+		assert_eq!(quota.lock().reserved, 0);
+	}
+
+	#[test]
+	fn add_file_and_release() {
+		let quota = Quota::new(None, Some(2), Usage { bytes: 10, files: 1, reserved: 0 });
+		assert!(quota.add_file());
+		assert!(!quota.add_file());
+		quota.release(4, 1);
+		assert!(quota.add_file());
+		quota.release(100, 5);
+		let usage = *quota.lock();
+		assert_eq!((usage.bytes, usage.files), (0, 0));
+	}
+
+	#[test]
+	fn reply_lists_usage_and_limits() {
+		let quota = Quota::new(Some(1000), None, Usage { bytes: 10, files: 1, reserved: 0 });
+		let reply = quota.reply("alice").to_bytes();
+		assert_eq!(String::from_utf8(reply).unwrap(), "200-Quota of alice\r\n Bytes: 10 used of 1000\r\n Files: 1 used of unlimited\r\n Reserved by ALLO: 0 bytes\r\n200 End of quota");
+	}
+}
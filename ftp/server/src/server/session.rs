@@ -0,0 +1,185 @@
+/* Copyright 2022 Pierrick MARIE
+
+This file is part of rust-discovery
+
+LCS is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+Rust-discovery is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with rust-discovery.  If not, see <http://www.gnu.org/licenses/>. */
+
+use std::path::PathBuf;
+
+use crate::protocol::{ClientCommand, ServerResponse};
+
+/**
+ * State of a session, it tells which commands are expected.
+ * RNFR and REST only apply to the command which follows them: any other command goes back to LoggedIn.
+ * PORT, PASV, EPRT and EPSV may come between REST and the transfer, they keep the restart marker.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub enum SessionState {
+	AwaitingUser,
+	AwaitingPassword(String), // user name given by USER
+	LoggedIn,
+	RenamePending(PathBuf), // virtual path given by RNFR, waiting for RNTO
+	RestartPending(u64), // offset given by REST, waiting for RETR or STOR
+	Closing, // The server sent 421, the control connection is closed after the current command
+}
+
+impl SessionState {
+	pub fn is_logged_in(&self) -> bool {
+		!matches!(self, SessionState::AwaitingUser | SessionState::AwaitingPassword(_) | SessionState::Closing)
+	}
+
+	/**
+	 * The user name given by USER, if PASS comes right after it.
+	 * The session waits for USER again, whatever the password.
+	 */
+	pub fn take_login(&mut self) -> Option<String> {
+		match std::mem::replace(self, SessionState::AwaitingUser) {
+			SessionState::AwaitingPassword(login) => Some(login),
+			_ => None,
+		}
+	}
+
+	/**
+	 * Reply to a command which needs a logged user, sent before the login
+	 */
+	pub fn not_logged_in_reply(&self) -> (ServerResponse, &'static str) {
+		match self {
+			SessionState::AwaitingPassword(_) => (ServerResponse::BadSequenceOfCommands, "Login with PASS"),
+			_ => (ServerResponse::NotLoggedIn, "Not logged in"),
+		}
+	}
+
+	/**
+	 * Start a command of a logged user: the state goes back to LoggedIn, the command may set another one.
+	 * Returns the state before the command.
+	 */
+	pub fn start_command(&mut self) -> SessionState {
+		std::mem::replace(self, SessionState::LoggedIn)
+	}
+
+	/**
+	 * The state to restore once command is done: the restart marker if command opens the data connection
+	 */
+	pub fn kept_by(&self, command: &ClientCommand) -> Option<SessionState> {
+		match (command, self) {
+			(ClientCommand::Pasv | ClientCommand::Epsv(_) | ClientCommand::Port(_) | ClientCommand::Eprt(_),
+				SessionState::RestartPending(_)) => Some(self.clone()),
+			_ => None,
+		}
+	}
+
+	/**
+	 * Restore the state given by kept_by, unless the command set a new state
+	 */
+	pub fn restore(&mut self, kept: Option<SessionState>) {
+		if let Some(kept) = kept.filter(|_| *self == SessionState::LoggedIn) {
+			*self = kept;
+		}
+	}
+
+	/**
+	 * Offset of the restart marker if the previous command was REST
+	 */
+	pub fn restart_offset(&self) -> u64 {
+		match self {
+			SessionState::RestartPending(offset) => *offset,
+			_ => 0,
+		}
+	}
+
+	/**
+	 * Path given by RNFR if it was the previous command
+	 */
+	pub fn rename_origin(self) -> Option<PathBuf> {
+		match self {
+			SessionState::RenamePending(path) => Some(path),
+			_ => None,
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn pass_needs_user_first() {
+		let mut state = SessionState::AwaitingUser;
+		assert_eq!(state.take_login(), None);
+		assert_eq!(state.not_logged_in_reply().0 as u32, 530);
+
+		let mut state = SessionState::AwaitingPassword("alice".to_string());
+		assert_eq!(state.not_logged_in_reply().0 as u32, 503);
+		assert!(!state.is_logged_in());
+		assert_eq!(state.take_login(), Some("alice".to_string()));
+		// A second PASS needs USER again
+		assert_eq!(state, SessionState::AwaitingUser);
+		assert_eq!(state.take_login(), None);
+	}
+
+	#[test]
+	fn logged_in_states() {
+		assert!(SessionState::LoggedIn.is_logged_in());
+		assert!(SessionState::RenamePending(PathBuf::from("/a")).is_logged_in());
+		assert!(SessionState::RestartPending(10).is_logged_in());
+		// Where REIN and a failed login go back to
+		assert!(!SessionState::AwaitingUser.is_logged_in());
+		assert!(!SessionState::Closing.is_logged_in());
+	}
+
+	#[test]
+	fn rnto_follows_rnfr() {
+		let mut state = SessionState::RenamePending(PathBuf::from("/a.txt"));
+		let previous = state.start_command();
+		assert_eq!(state, SessionState::LoggedIn);
+		assert_eq!(previous.rename_origin(), Some(PathBuf::from("/a.txt")));
+
+		// Any command between RNFR and RNTO cancels the rename
+		let mut state = SessionState::RenamePending(PathBuf::from("/a.txt"));
+		let kept = state.start_command().kept_by(&ClientCommand::NoOp);
+		state.restore(kept);
+		assert_eq!(state.start_command().rename_origin(), None);
+	}
+
+	#[test]
+	fn rest_applies_to_the_transfer() {
+		let mut state = SessionState::RestartPending(100);
+		// The data connection is opened between REST and RETR
+		let previous = state.start_command();
+		let kept = previous.kept_by(&ClientCommand::Epsv(None));
+		state.restore(kept);
+		assert_eq!(state.restart_offset(), 100);
+
+		let previous = state.start_command();
+		assert_eq!(previous.restart_offset(), 100);
+		state.restore(previous.kept_by(&ClientCommand::Retr(PathBuf::from("/a.txt"))));
+		assert_eq!(state, SessionState::LoggedIn);
+		assert_eq!(state.restart_offset(), 0);
+	}
+
+	#[test]
+	fn rest_is_dropped_by_other_commands() {
+		let mut state = SessionState::RestartPending(100);
+		let kept = state.start_command().kept_by(&ClientCommand::NoOp);
+		state.restore(kept);
+		assert_eq!(state.start_command().restart_offset(), 0);
+
+		// A command which sets a new state is not overwritten by the restart marker
+		let mut state = SessionState::RestartPending(100);
+		let kept = state.start_command().kept_by(&ClientCommand::Pasv);
+		state = SessionState::Closing;
+		state.restore(kept);
+		assert_eq!(state, SessionState::Closing);
+	}
+}
@@ -0,0 +1,190 @@
+/* Copyright 2022 Pierrick MARIE
+
+This file is part of rust-discovery
+
+LCS is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+Rust-discovery is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with rust-discovery.  If not, see <http://www.gnu.org/licenses/>. */
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/**
+ * Token bucket: bytes pass at rate bytes per second, with bursts of one second at most.
+ * Bytes taken beyond the available tokens are a debt, the transfer pays it by waiting.
+ */
+pub struct TokenBucket {
+	rate: u64,
+	state: Mutex<BucketState>,
+}
+
+struct BucketState {
+	tokens: f64, // Negative when bytes were taken in advance
+	updated: Instant,
+}
+
+impl TokenBucket {
+	pub fn new(rate: u64) -> Self {
+		TokenBucket {
+			rate,
+			state: Mutex::new(BucketState {
+				tokens: rate as f64,
+				updated: Instant::now(),
+			}),
+		}
+	}
+
+	/**
+	 * Take the tokens of bytes, returns how long to wait before the next bytes
+	 */
+	fn take(&self, bytes: usize) -> Duration {
+		let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+		let now = Instant::now();
+		let rate = self.rate as f64;
+		state.tokens = (state.tokens + now.duration_since(state.updated).as_secs_f64() * rate).min(rate);
+		state.updated = now;
+		state.tokens -= bytes as f64;
+		if state.tokens >= 0.0 {
+			Duration::ZERO
+		} else {
+			Duration::from_secs_f64(-state.tokens / rate)
+		}
+	}
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Direction {
+	Download,
+	Upload,
+}
+
+/**
+ * Download and upload limits of one level: a session, all the sessions of a user, or the whole server.
+ * None if the level has no limit in that direction.
+ */
+pub struct Bandwidth {
+	download: Option<Arc<TokenBucket>>,
+	upload: Option<Arc<TokenBucket>>,
+}
+
+impl Bandwidth {
+	pub fn new(download: Option<u64>, upload: Option<u64>) -> Self {
+		Bandwidth {
+			download: download.map(|rate| Arc::new(TokenBucket::new(rate))),
+			upload: upload.map(|rate| Arc::new(TokenBucket::new(rate))),
+		}
+	}
+
+	fn bucket(&self, direction: Direction) -> Option<&Arc<TokenBucket>> {
+		match direction {
+			Direction::Download => self.download.as_ref(),
+			Direction::Upload => self.upload.as_ref(),
+		}
+	}
+}
+
+/**
+ * Every bucket a transfer goes through, in one direction. Without bucket, the transfer is not limited.
+ */
+#[derive(Clone, Default)]
+pub struct Throttle {
+	buckets: Vec<Arc<TokenBucket>>,
+}
+
+impl Throttle {
+	pub fn new(levels: &[&Bandwidth], direction: Direction) -> Self {
+		Throttle {
+			buckets: levels.iter().filter_map(|level| level.bucket(direction).cloned()).collect(),
+		}
+	}
+
+	/**
+	 * The effective limit, in bytes per second: the lowest one
+	 */
+	pub fn limit(&self) -> Option<u64> {
+		self.buckets.iter().map(|bucket| bucket.rate).min()
+	}
+
+	/**
+	 * Count bytes just transferred, then wait as long as the most limited level requires
+	 */
+	pub async fn consume(&self, bytes: usize) {
+		let delay = self.buckets.iter().map(|bucket| bucket.take(bytes)).max().unwrap_or(Duration::ZERO);
+		if !delay.is_zero() {
+			tokio::time::sleep(delay).await;
+		}
+	}
+
+	/**
+	 * Line of the STAT reply
+	 */
+	pub fn status(&self, direction: Direction) -> String {
+		let name = match direction {
+			Direction::Download => "download",
+			Direction::Upload => "upload",
+		};
+		match self.limit() {
+			Some(limit) => format!("Bandwidth limit for {} is {} bytes per second", name, limit),
+			None => format!("No {} bandwidth limit", name),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn bucket_allows_one_second_of_burst_then_makes_a_debt() {
+		let bucket = TokenBucket::new(1000);
+		assert_eq!(bucket.take(600), Duration::ZERO);
+		assert_eq!(bucket.take(400), Duration::ZERO);
+		// 500 bytes beyond the tokens: half a second to wait, a little less since time went by
+		let delay = bucket.take(500);
+		assert!(delay > Duration::from_millis(450) && delay <= Duration::from_millis(500), "{:?}", delay);
+	}
+
+	#[test]
+	fn bucket_refills_with_time() {
+		let bucket = TokenBucket::new(1000);
+		bucket.take(1000);
+		bucket.state.lock().unwrap().updated -= Duration::from_millis(300);
+		assert_eq!(bucket.take(250), Duration::ZERO);
+	}
+
+	#[test]
+	fn throttle_takes_the_lowest_limit() {
+		let session = Bandwidth::new(Some(1000), None);
+		let user = Bandwidth::new(Some(500), Some(2000));
+		let server = Bandwidth::new(None, None);
+		let download = Throttle::new(&[&session, &user, &server], Direction::Download);
+		let upload = Throttle::new(&[&session, &user, &server], Direction::Upload);
+		assert_eq!(download.limit(), Some(500));
+		assert_eq!(upload.limit(), Some(2000));
+		assert_eq!(download.status(Direction::Download), "Bandwidth limit for download is 500 bytes per second");
+		let none = Throttle::new(&[&server], Direction::Upload);
+		assert_eq!(none.limit(), None);
+		assert_eq!(none.status(Direction::Upload), "No upload bandwidth limit");
+	}
+
+	#[tokio::test]
+	async fn consume_waits_for_the_most_limited_level() {
+		let fast = Bandwidth::new(Some(1_000_000), None);
+		let slow = Bandwidth::new(Some(10_000), None);
+		let throttle = Throttle::new(&[&fast, &slow], Direction::Download);
+		let start = Instant::now();
+		throttle.consume(10_000).await;
+		assert!(start.elapsed() < Duration::from_millis(100), "{:?}", start.elapsed());
+		throttle.consume(2000).await;
+		assert!(start.elapsed() >= Duration::from_millis(190), "{:?}", start.elapsed());
+	}
+}
@@ -0,0 +1,84 @@
+/* Copyright 2022 Pierrick MARIE
+
+This file is part of rust-discovery
+
+LCS is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+Rust-discovery is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with rust-discovery.  If not, see <http://www.gnu.org/licenses/>. */
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::protocol::reply::Reply;
+use crate::protocol::ServerResponse;
+
+/**
+ * Progress of the running transfer, reported by STAT while the transfer goes on
+ */
+pub struct Progress {
+	command: String, // Command which started the transfer, with its argument
+	size: Option<u64>, // Bytes to transfer, unknown for uploads
+	transferred: AtomicU64,
+}
+
+impl Progress {
+	pub fn new(command: String, size: Option<u64>) -> Self {
+		Progress {
+			command,
+			size,
+			transferred: AtomicU64::new(0),
+		}
+	}
+
+	pub fn add(&self, bytes: usize) {
+		self.transferred.fetch_add(bytes as u64, Ordering::Relaxed);
+	}
+
+	pub fn command(&self) -> &str {
+		self.command.as_str()
+	}
+
+	pub fn reply(&self) -> Reply {
+		let transferred = self.transferred.load(Ordering::Relaxed);
+		let status = match self.size {
+			Some(size) => format!("{} of {} bytes transferred", transferred, size),
+			None => format!("{} bytes transferred", transferred),
+		};
+		Reply::new(ServerResponse::FileStatus)
+			.line("Transfer in progress")
+			.line(self.command.as_str())
+			.line(status)
+			.line("End of status")
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn reply_with_known_size() {
+		let progress = Progress::new("RETR /a.txt".to_string(), Some(100));
+		progress.add(30);
+		progress.add(12);
+		assert_eq!(progress.reply().to_bytes(),
+			b"213-Transfer in progress\r\n RETR /a.txt\r\n 42 of 100 bytes transferred\r\n213 End of status");
+	}
+
+	#[test]
+	fn reply_of_an_upload() {
+		let progress = Progress::new("STOR /b.txt".to_string(), None);
+		progress.add(7);
+		assert_eq!(progress.command(), "STOR /b.txt");
+		assert_eq!(progress.reply().to_bytes(),
+			b"213-Transfer in progress\r\n STOR /b.txt\r\n 7 bytes transferred\r\n213 End of status");
+	}
+}
@@ -0,0 +1,154 @@
+/* Copyright 2022 Pierrick MARIE
+
+This file is part of rust-discovery
+
+LCS is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+Rust-discovery is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with rust-discovery.  If not, see <http://www.gnu.org/licenses/>. */
+
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Error, ErrorKind, Seek, SeekFrom};
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+
+use log::debug;
+
+use crate::storage::{DirEntry, Metadata, ReadHandle, Storage, WriteHandle};
+use crate::utils::jail::Jail;
+
+/**
+ * Files stored in a directory of the local file system, the root directory of the user.
+ * The jail checks every path, symbolic links included.
+ * DELE, RNFR, RNTO and STOR act on a symbolic link itself, the other commands on its target.
+ */
+pub struct LocalStorage {
+	jail: Jail,
+}
+
+impl LocalStorage {
+	pub fn new(root: &Path) -> io::Result<Self> {
+		Ok(LocalStorage {
+			jail: Jail::new(root)?,
+		})
+	}
+
+	fn real_path(&self, path: &Path) -> io::Result<PathBuf> {
+		self.jail.real_path(path).ok_or_else(outside_root)
+	}
+
+	fn resolved_path(&self, path: &Path) -> io::Result<PathBuf> {
+		self.jail.resolved_path(path).ok_or_else(outside_root)
+	}
+}
+
+fn outside_root() -> Error {
+	Error::new(ErrorKind::PermissionDenied, "Path outside the root directory")
+}
+
+impl From<fs::Metadata> for Metadata {
+	fn from(metadata: fs::Metadata) -> Self {
+		Metadata {
+			is_dir: metadata.is_dir(),
+			is_file: metadata.is_file(),
+			len: metadata.len(),
+			modified: metadata.modified().unwrap_or(std::time::UNIX_EPOCH),
+			mode: metadata.mode(),
+			uid: metadata.uid(),
+			gid: metadata.gid(),
+			device: metadata.dev(),
+			inode: metadata.ino(),
+		}
+	}
+}
+
+impl Storage for LocalStorage {
+	fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+		Ok(fs::metadata(self.resolved_path(path)?)?.into())
+	}
+
+	fn link_metadata(&self, path: &Path) -> io::Result<Metadata> {
+		Ok(fs::symlink_metadata(self.real_path(path)?)?.into())
+	}
+
+	fn list(&self, path: &Path) -> io::Result<Vec<DirEntry>> {
+		debug!("LocalStorage::list {:?}", path);
+		let mut entries = vec![];
+		for entry in fs::read_dir(self.resolved_path(path)?)?.flatten() {
+			// The metadata of the target of a symbolic link, dangling links are skipped
+			if let Ok(metadata) = fs::metadata(entry.path()) {
+				entries.push(DirEntry {
+					name: entry.file_name(),
+					metadata: metadata.into(),
+				});
+			}
+		}
+		Ok(entries)
+	}
+
+	fn open_read(&self, path: &Path, offset: u64) -> io::Result<ReadHandle> {
+		let mut file = File::open(self.resolved_path(path)?)?;
+		file.seek(SeekFrom::Start(offset))?;
+		Ok(Box::new(tokio::fs::File::from_std(file)))
+	}
+
+	fn open_write(&self, path: &Path, offset: u64) -> io::Result<WriteHandle> {
+		let path = if offset == 0 {
+			let path = self.real_path(path)?;
+			if fs::symlink_metadata(&path).is_ok_and(|metadata| metadata.file_type().is_symlink()) {
+				fs::remove_file(&path)?;
+			}
+			path
+		} else {
+			self.resolved_path(path)?
+		};
+		let mut file = OpenOptions::new()
+			.write(true)
+			.create(true)
+			.truncate(offset == 0)
+			.custom_flags(if offset == 0 { libc::O_NOFOLLOW } else { 0 })
+			.open(path)?;
+		if offset > 0 {
+			file.set_len(offset)?;
+			file.seek(SeekFrom::Start(offset))?;
+		}
+		Ok(Box::new(tokio::fs::File::from_std(file)))
+	}
+
+	fn append(&self, path: &Path) -> io::Result<WriteHandle> {
+		let file = OpenOptions::new()
+			.append(true)
+			.create(true)
+			.open(self.resolved_path(path)?)?;
+		Ok(Box::new(tokio::fs::File::from_std(file)))
+	}
+
+	fn create_dir(&self, path: &Path) -> io::Result<()> {
+		fs::create_dir(self.real_path(path)?)
+	}
+
+	fn remove_file(&self, path: &Path) -> io::Result<()> {
+		fs::remove_file(self.real_path(path)?)
+	}
+
+	fn remove_dir(&self, path: &Path) -> io::Result<()> {
+		fs::remove_dir(self.real_path(path)?)
+	}
+
+	fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+		fs::rename(self.real_path(from)?, self.real_path(to)?)
+	}
+
+	fn local_file(&self, path: &Path) -> io::Result<Option<File>> {
+		Ok(Some(File::open(self.resolved_path(path)?)?))
+	}
+}
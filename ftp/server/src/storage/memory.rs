@@ -0,0 +1,378 @@
+/* Copyright 2022 Pierrick MARIE
+
+This file is part of rust-discovery
+
+LCS is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+Rust-discovery is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with rust-discovery.  If not, see <http://www.gnu.org/licenses/>. */
+
+use std::collections::BTreeMap;
+use std::io::{self, Cursor, Error, ErrorKind};
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{Context, Poll};
+use std::time::SystemTime;
+
+use tokio::io::AsyncWrite;
+
+use crate::storage::{DirEntry, Metadata, ReadHandle, Storage, WriteHandle};
+
+const DIRECTORY_MODE: u32 = 0o40755;
+const FILE_MODE: u32 = 0o100644;
+
+struct MemoryFile {
+	content: Vec<u8>,
+	modified: SystemTime,
+}
+
+/**
+ * A node of the tree. The content of a file is shared with the handles opened to write it.
+ */
+#[derive(Clone)]
+enum Node {
+	Directory { inode: u64, modified: SystemTime },
+	File { inode: u64, file: Arc<Mutex<MemoryFile>> },
+}
+
+/**
+ * Files kept in memory and lost when the server stops: the server runs without touching the disk, useful for tests.
+ * The nodes are indexed by their virtual path, so the entries of a directory follow it in the map.
+ */
+pub struct MemoryStorage {
+	nodes: Mutex<BTreeMap<PathBuf, Node>>,
+	next_inode: AtomicU64,
+}
+
+impl Default for MemoryStorage {
+	fn default() -> Self {
+		let mut nodes = BTreeMap::new();
+		nodes.insert(PathBuf::from("/"), Node::Directory { inode: 1, modified: SystemTime::now() });
+		MemoryStorage {
+			nodes: Mutex::new(nodes),
+			next_inode: AtomicU64::new(2),
+		}
+	}
+}
+
+impl MemoryStorage {
+	pub fn new() -> Self {
+		MemoryStorage::default()
+	}
+
+	fn nodes(&self) -> MutexGuard<'_, BTreeMap<PathBuf, Node>> {
+		// A panic while the lock is held cannot leave the map half updated.
+		self.nodes.lock().unwrap_or_else(|e| e.into_inner())
+	}
+
+	/**
+	 * Add a file, the parent directory must exist
+	 */
+	fn create_file(&self, nodes: &mut BTreeMap<PathBuf, Node>, path: &Path) -> io::Result<Arc<Mutex<MemoryFile>>> {
+		check_parent(nodes, path)?;
+		let file = Arc::new(Mutex::new(MemoryFile { content: vec![], modified: SystemTime::now() }));
+		let inode = self.next_inode.fetch_add(1, Ordering::SeqCst);
+		nodes.insert(path.to_path_buf(), Node::File { inode, file: file.clone() });
+		Ok(file)
+	}
+
+	/**
+	 * The existing file at path, or a new one
+	 */
+	fn open_file(&self, path: &Path) -> io::Result<Arc<Mutex<MemoryFile>>> {
+		let mut nodes = self.nodes();
+		match nodes.get(path) {
+			Some(Node::File { file, .. }) => Ok(file.clone()),
+			Some(Node::Directory { .. }) => Err(Error::new(ErrorKind::IsADirectory, "Is a directory")),
+			None => self.create_file(&mut nodes, path),
+		}
+	}
+}
+
+fn not_found() -> Error {
+	Error::new(ErrorKind::NotFound, "No such file or directory")
+}
+
+fn check_parent(nodes: &BTreeMap<PathBuf, Node>, path: &Path) -> io::Result<()> {
+	match nodes.get(path.parent().ok_or_else(not_found)?) {
+		Some(Node::Directory { .. }) => Ok(()),
+		Some(Node::File { .. }) => Err(Error::new(ErrorKind::NotADirectory, "Not a directory")),
+		None => Err(not_found()),
+	}
+}
+
+/**
+ * Paths of the nodes below path, path excluded
+ */
+fn descendants(nodes: &BTreeMap<PathBuf, Node>, path: &Path) -> Vec<PathBuf> {
+	nodes.range::<Path, _>((Bound::Excluded(path), Bound::Unbounded))
+		.take_while(|(key, _)| key.starts_with(path))
+		.map(|(key, _)| key.clone())
+		.collect()
+}
+
+impl Node {
+	fn metadata(&self) -> Metadata {
+		match self {
+			Node::Directory { inode, modified } => Metadata {
+				is_dir: true,
+				is_file: false,
+				len: 0,
+				modified: *modified,
+				mode: DIRECTORY_MODE,
+				uid: 0,
+				gid: 0,
+				device: 0,
+				inode: *inode,
+			},
+			Node::File { inode, file } => {
+				let file = file.lock().unwrap_or_else(|e| e.into_inner());
+				Metadata {
+					is_dir: false,
+					is_file: true,
+					len: file.content.len() as u64,
+					modified: file.modified,
+					mode: FILE_MODE,
+					uid: 0,
+					gid: 0,
+					device: 0,
+					inode: *inode,
+				}
+			}
+		}
+	}
+}
+
+impl Storage for MemoryStorage {
+	fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+		self.nodes().get(path).map(Node::metadata).ok_or_else(not_found)
+	}
+
+	fn list(&self, path: &Path) -> io::Result<Vec<DirEntry>> {
+		let nodes = self.nodes();
+		match nodes.get(path) {
+			Some(Node::Directory { .. }) => {}
+			Some(Node::File { .. }) => return Err(Error::new(ErrorKind::NotADirectory, "Not a directory")),
+			None => return Err(not_found()),
+		}
+		Ok(descendants(&nodes, path).iter()
+			.filter(|key| key.parent() == Some(path))
+			.map(|key| DirEntry {
+				name: key.file_name().unwrap_or_default().to_os_string(),
+				metadata: nodes[key].metadata(),
+			})
+			.collect())
+	}
+
+	fn open_read(&self, path: &Path, offset: u64) -> io::Result<ReadHandle> {
+		match self.nodes().get(path) {
+			Some(Node::File { file, .. }) => {
+				// The download reads the content the file had when it was opened
+				let mut cursor = Cursor::new(file.lock().unwrap_or_else(|e| e.into_inner()).content.clone());
+				cursor.set_position(offset);
+				Ok(Box::new(cursor))
+			}
+			Some(Node::Directory { .. }) => Err(Error::new(ErrorKind::IsADirectory, "Is a directory")),
+			None => Err(not_found()),
+		}
+	}
+
+	fn open_write(&self, path: &Path, offset: u64) -> io::Result<WriteHandle> {
+		let file = self.open_file(path)?;
+		file.lock().unwrap_or_else(|e| e.into_inner()).content.resize(offset as usize, 0);
+		Ok(Box::new(MemoryWriter { file, position: offset as usize }))
+	}
+
+	fn append(&self, path: &Path) -> io::Result<WriteHandle> {
+		let file = self.open_file(path)?;
+		let position = file.lock().unwrap_or_else(|e| e.into_inner()).content.len();
+		Ok(Box::new(MemoryWriter { file, position }))
+	}
+
+	fn create_dir(&self, path: &Path) -> io::Result<()> {
+		let mut nodes = self.nodes();
+		if nodes.contains_key(path) {
+			return Err(Error::new(ErrorKind::AlreadyExists, "File exists"));
+		}
+		check_parent(&nodes, path)?;
+		let inode = self.next_inode.fetch_add(1, Ordering::SeqCst);
+		nodes.insert(path.to_path_buf(), Node::Directory { inode, modified: SystemTime::now() });
+		Ok(())
+	}
+
+	fn remove_file(&self, path: &Path) -> io::Result<()> {
+		let mut nodes = self.nodes();
+		match nodes.get(path) {
+			Some(Node::File { .. }) => {
+				nodes.remove(path);
+				Ok(())
+			}
+			Some(Node::Directory { .. }) => Err(Error::new(ErrorKind::IsADirectory, "Is a directory")),
+			None => Err(not_found()),
+		}
+	}
+
+	fn remove_dir(&self, path: &Path) -> io::Result<()> {
+		let mut nodes = self.nodes();
+		match nodes.get(path) {
+			Some(Node::Directory { .. }) if path.parent().is_none() => {
+				Err(Error::new(ErrorKind::PermissionDenied, "Cannot remove the root directory"))
+			}
+			Some(Node::Directory { .. }) => {
+				if !descendants(&nodes, path).is_empty() {
+					return Err(Error::new(ErrorKind::DirectoryNotEmpty, "Directory not empty"));
+				}
+				nodes.remove(path);
+				Ok(())
+			}
+			Some(Node::File { .. }) => Err(Error::new(ErrorKind::NotADirectory, "Not a directory")),
+			None => Err(not_found()),
+		}
+	}
+
+	fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+		let mut nodes = self.nodes();
+		let node = nodes.get(from).ok_or_else(not_found)?.clone();
+		if from == to {
+			return Ok(());
+		}
+		if to.starts_with(from) {
+			return Err(Error::new(ErrorKind::InvalidInput, "Cannot move a directory into itself"));
+		}
+		check_parent(&nodes, to)?;
+		match (&node, nodes.get(to)) {
+			(_, None) | (Node::File { .. }, Some(Node::File { .. })) => {}
+			(_, Some(Node::Directory { .. })) => return Err(Error::new(ErrorKind::AlreadyExists, "File exists")),
+			(Node::Directory { .. }, Some(Node::File { .. })) => return Err(Error::new(ErrorKind::NotADirectory, "Not a directory")),
+		}
+
+		// A directory is moved with everything below it
+		for key in descendants(&nodes, from) {
+			if let Some(child) = nodes.remove(&key) {
+				nodes.insert(to.join(key.strip_prefix(from).unwrap()), child);
+			}
+		}
+		nodes.remove(from);
+		nodes.insert(to.to_path_buf(), node);
+		Ok(())
+	}
+}
+
+/**
+ * Write into the shared content of a file, from the position given when it was opened.
+ */
+struct MemoryWriter {
+	file: Arc<Mutex<MemoryFile>>,
+	position: usize,
+}
+
+impl AsyncWrite for MemoryWriter {
+	fn poll_write(mut self: Pin<&mut Self>, _cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+		let position = self.position;
+		let mut file = self.file.lock().unwrap_or_else(|e| e.into_inner());
+		let end = position + buf.len();
+		if file.content.len() < end {
+			file.content.resize(end, 0);
+		}
+		file.content[position..end].copy_from_slice(buf);
+		file.modified = SystemTime::now();
+		drop(file);
+		self.position = end;
+		Poll::Ready(Ok(buf.len()))
+	}
+
+	fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		Poll::Ready(Ok(()))
+	}
+
+	fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		Poll::Ready(Ok(()))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+	async fn write(storage: &MemoryStorage, path: &str, offset: u64, content: &[u8]) {
+		let mut file = storage.open_write(Path::new(path), offset).unwrap();
+		file.write_all(content).await.unwrap();
+	}
+
+	async fn read(storage: &MemoryStorage, path: &str, offset: u64) -> Vec<u8> {
+		let mut content = vec![];
+		storage.open_read(Path::new(path), offset).unwrap().read_to_end(&mut content).await.unwrap();
+		content
+	}
+
+	fn names(storage: &MemoryStorage, path: &str) -> Vec<String> {
+		let mut names: Vec<_> = storage.list(Path::new(path)).unwrap().iter()
+			.map(|entry| entry.name.to_string_lossy().to_string())
+			.collect();
+		names.sort();
+		names
+	}
+
+	#[tokio::test]
+	async fn write_read_and_append() {
+		let storage = MemoryStorage::new();
+		write(&storage, "/a.txt", 0, b"hello world").await;
+		assert_eq!(read(&storage, "/a.txt", 6).await, b"world");
+		// Restart at an offset: what follows it is replaced
+		write(&storage, "/a.txt", 5, b"!").await;
+		assert_eq!(read(&storage, "/a.txt", 0).await, b"hello!");
+		storage.append(Path::new("/a.txt")).unwrap().write_all(b"?").await.unwrap();
+		assert_eq!(storage.metadata(Path::new("/a.txt")).unwrap().len, 7);
+		assert!(storage.open_write(Path::new("/missing/a.txt"), 0).is_err());
+	}
+
+	#[test]
+	fn list_direct_entries_only() {
+		let storage = MemoryStorage::new();
+		storage.create_dir(Path::new("/a")).unwrap();
+		storage.create_dir(Path::new("/a/b")).unwrap();
+		storage.create_dir(Path::new("/ab")).unwrap();
+		assert_eq!(names(&storage, "/"), vec!["a", "ab"]);
+		assert_eq!(names(&storage, "/a"), vec!["b"]);
+		assert_eq!(storage.create_dir(Path::new("/a")).unwrap_err().kind(), ErrorKind::AlreadyExists);
+		assert_eq!(storage.create_dir(Path::new("/c/d")).unwrap_err().kind(), ErrorKind::NotFound);
+	}
+
+	#[tokio::test]
+	async fn remove_files_and_empty_directories() {
+		let storage = MemoryStorage::new();
+		storage.create_dir(Path::new("/a")).unwrap();
+		write(&storage, "/a/f", 0, b"x").await;
+		assert_eq!(storage.remove_dir(Path::new("/a")).unwrap_err().kind(), ErrorKind::DirectoryNotEmpty);
+		assert_eq!(storage.remove_file(Path::new("/a")).unwrap_err().kind(), ErrorKind::IsADirectory);
+		storage.remove_file(Path::new("/a/f")).unwrap();
+		storage.remove_dir(Path::new("/a")).unwrap();
+		assert_eq!(storage.remove_dir(Path::new("/")).unwrap_err().kind(), ErrorKind::PermissionDenied);
+		assert!(names(&storage, "/").is_empty());
+	}
+
+	#[tokio::test]
+	async fn rename_moves_a_directory_with_its_content() {
+		let storage = MemoryStorage::new();
+		storage.create_dir(Path::new("/a")).unwrap();
+		storage.create_dir(Path::new("/ab")).unwrap();
+		write(&storage, "/a/f", 0, b"x").await;
+		storage.rename(Path::new("/a"), Path::new("/b")).unwrap();
+		assert_eq!(names(&storage, "/"), vec!["ab", "b"]);
+		assert_eq!(read(&storage, "/b/f", 0).await, b"x");
+		assert_eq!(storage.rename(Path::new("/b"), Path::new("/b/c")).unwrap_err().kind(), ErrorKind::InvalidInput);
+		assert_eq!(storage.rename(Path::new("/b/f"), Path::new("/ab")).unwrap_err().kind(), ErrorKind::AlreadyExists);
+	}
+}
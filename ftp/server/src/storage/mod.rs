@@ -0,0 +1,116 @@
+/* Copyright 2022 Pierrick MARIE
+
+This file is part of rust-discovery
+
+LCS is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+Rust-discovery is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with rust-discovery.  If not, see <http://www.gnu.org/licenses/>. */
+
+use std::ffi::OsString;
+use std::fs::File;
+use std::io;
+use std::path::Path;
+use std::time::SystemTime;
+
+use tokio::io::{AsyncRead, AsyncWrite};
+
+pub mod local;
+pub mod memory;
+
+/**
+ * Content of a file opened for a download, read from the requested offset.
+ */
+pub type ReadHandle = Box<dyn AsyncRead + Send + Unpin>;
+
+/**
+ * Content of a file opened for an upload.
+ */
+pub type WriteHandle = Box<dyn AsyncWrite + Send + Unpin>;
+
+/**
+ * What the commands need to know about a file or a directory.
+ */
+#[derive(Debug, Clone)]
+pub struct Metadata {
+	pub is_dir: bool,
+	pub is_file: bool, // Both false for a special file, which cannot be transferred
+	pub len: u64,
+	pub modified: SystemTime,
+	pub mode: u32, // Unix mode: type and permission bits
+	pub uid: u32,
+	pub gid: u32,
+	pub device: u64, // With inode, identifies the file (unique fact of MLST)
+	pub inode: u64,
+}
+
+/**
+ * An entry of a directory, hidden files included.
+ */
+#[derive(Debug, Clone)]
+pub struct DirEntry {
+	pub name: OsString, // Not always valid UTF-8
+	pub metadata: Metadata,
+}
+
+/**
+ * Where the files of a user are stored.
+ *
+ * Every path is a virtual absolute path built by `Jail::normalize`: "/" is the root directory of the user.
+ * A storage never gives access to anything outside this root directory.
+ */
+pub trait Storage: Send + Sync {
+	fn metadata(&self, path: &Path) -> io::Result<Metadata>;
+
+	/**
+	 * Metadata of the path itself: a symbolic link is not followed
+	 */
+	fn link_metadata(&self, path: &Path) -> io::Result<Metadata> {
+		self.metadata(path)
+	}
+
+	/**
+	 * Entries of a directory, without "." and ".."
+	 */
+	fn list(&self, path: &Path) -> io::Result<Vec<DirEntry>>;
+
+	fn open_read(&self, path: &Path, offset: u64) -> io::Result<ReadHandle>;
+
+	/**
+	 * Create the file, or cut an existing file at offset: the data written replace everything after offset.
+	 * At offset 0, a symbolic link is replaced by the new file instead of writing to its target.
+	 */
+	fn open_write(&self, path: &Path, offset: u64) -> io::Result<WriteHandle>;
+
+	/**
+	 * Open the file to write at its end, it is created if it doesn't exist.
+	 */
+	fn append(&self, path: &Path) -> io::Result<WriteHandle>;
+
+	fn create_dir(&self, path: &Path) -> io::Result<()>;
+
+	fn remove_file(&self, path: &Path) -> io::Result<()>;
+
+	/**
+	 * Remove an empty directory
+	 */
+	fn remove_dir(&self, path: &Path) -> io::Result<()>;
+
+	fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+
+	/**
+	 * The file opened on the local file system, for the zero-copy downloads.
+	 * None if the storage doesn't keep its files on the local file system.
+	 */
+	fn local_file(&self, _path: &Path) -> io::Result<Option<File>> {
+		Ok(None)
+	}
+}
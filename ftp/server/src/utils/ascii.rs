@@ -0,0 +1,135 @@
+/* Copyright 2022 Pierrick MARIE
+
+This file is part of rust-discovery
+
+LCS is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+Rust-discovery is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with rust-discovery.  If not, see <http://www.gnu.org/licenses/>. */
+
+use std::io;
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/*
+ASCII type conversions, see RFC 959 section 3.1.1.1:
+the end of line is CRLF on the wire and LF on the local file system.
+*/
+
+/**
+ * Convert local end of lines (LF) into network end of lines (CRLF).
+ */
+pub fn to_network(data: &[u8]) -> Vec<u8> {
+	let mut result = Vec::with_capacity(data.len() + data.len() / 32);
+	for byte in data {
+		if *byte == b'\n' {
+			result.push(b'\r');
+		}
+		result.push(*byte);
+	}
+	result
+}
+
+/**
+ * Size of a local file once converted with to_network: one more byte per LF.
+ * The whole file is read, SIZE in ASCII mode costs as much as downloading it.
+ */
+pub async fn network_size<R: AsyncRead + Unpin>(mut reader: R) -> io::Result<u64> {
+	let mut buffer = [0; 64 * 1024];
+	let mut size = 0;
+	loop {
+		let len = reader.read(&mut buffer).await?;
+		if len == 0 {
+			return Ok(size);
+		}
+		size += len as u64 + buffer[..len].iter().filter(|byte| **byte == b'\n').count() as u64;
+	}
+}
+
+/**
+ * Convert network end of lines (CRLF) into local end of lines (LF).
+ * A CR at the end of a chunk is kept until the next chunk tells if it starts a CRLF.
+ */
+#[derive(Default)]
+pub struct AsciiDecoder {
+	pending_cr: bool,
+}
+
+impl AsciiDecoder {
+	pub fn new() -> Self {
+		AsciiDecoder::default()
+	}
+
+	pub fn decode(&mut self, data: &[u8]) -> Vec<u8> {
+		let mut result = Vec::with_capacity(data.len() + 1);
+		for byte in data {
+			if self.pending_cr {
+				self.pending_cr = false;
+				if *byte != b'\n' {
+					result.push(b'\r');
+				}
+			}
+			if *byte == b'\r' {
+				self.pending_cr = true;
+			} else {
+				result.push(*byte);
+			}
+		}
+		result
+	}
+
+	/**
+	 * Bytes kept back at the end of the transfer.
+	 */
+	pub fn finish(&mut self) -> Vec<u8> {
+		if self.pending_cr {
+			self.pending_cr = false;
+			vec![b'\r']
+		} else {
+			vec![]
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn to_network_adds_cr_before_lf() {
+		assert_eq!(to_network(b"a\nb\n\n"), b"a\r\nb\r\n\r\n");
+		assert_eq!(to_network(b"no end of line"), b"no end of line");
+	}
+
+	#[tokio::test]
+	async fn network_size_counts_the_added_cr() {
+		let data = b"one\ntwo\nthree";
+		assert_eq!(network_size(&data[..]).await.unwrap(), to_network(data).len() as u64);
+		assert_eq!(network_size(&b""[..]).await.unwrap(), 0);
+	}
+
+	#[test]
+	fn decode_removes_cr_before_lf_only() {
+		let mut decoder = AsciiDecoder::new();
+		assert_eq!(decoder.decode(b"a\r\nb\rc\r\n"), b"a\nb\rc\n");
+		assert!(decoder.finish().is_empty());
+	}
+
+	#[test]
+	fn decode_crlf_split_between_chunks() {
+		let mut decoder = AsciiDecoder::new();
+		assert_eq!(decoder.decode(b"a\r"), b"a");
+		assert_eq!(decoder.decode(b"\nb\r"), b"\nb");
+		assert_eq!(decoder.decode(b"c"), b"\rc");
+		assert_eq!(decoder.decode(b"\r"), b"");
+		assert_eq!(decoder.finish(), b"\r");
+	}
+}
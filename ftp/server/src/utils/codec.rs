@@ -0,0 +1,195 @@
+/* Copyright 2022 Pierrick MARIE
+
+This file is part of rust-discovery
+
+LCS is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+Rust-discovery is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with rust-discovery.  If not, see <http://www.gnu.org/licenses/>. */
+
+use crate::utils::error::{FtpError, FtpResult};
+
+/*
+Telnet commands which may be sent on the control connection, see RFC 854.
+ABOR is sent after IAC IP and the Synch sequence IAC DM, the DM byte being urgent data.
+*/
+const IAC: u8 = 255;
+const DONT: u8 = 254;
+const DO: u8 = 253;
+const WONT: u8 = 252;
+const WILL: u8 = 251;
+const SE: u8 = 240;
+
+/**
+ * Split the bytes received on the control connection into command lines ended by CRLF.
+ * The bytes following a line are kept for the next one: a client can send several commands at once (pipelining)
+ * and a command can be received in several parts.
+ */
+pub struct LineCodec {
+	buffer: Vec<u8>,
+	max_length: usize,
+	discarding: bool, // True while the end of a too long line is received
+}
+
+impl LineCodec {
+	pub fn new(max_length: usize) -> Self {
+		LineCodec {
+			buffer: vec![],
+			max_length,
+			discarding: false,
+		}
+	}
+
+	pub fn extend(&mut self, data: &[u8]) {
+		self.buffer.extend_from_slice(data);
+	}
+
+	/**
+	 * Forget the bytes received but not decoded yet
+	 */
+	pub fn clear(&mut self) {
+		self.buffer.clear();
+		self.discarding = false;
+	}
+
+	/**
+	 * Next complete line without its end of line and Telnet commands, None if more bytes are needed.
+	 * A line longer than the maximum length is discarded and reported once.
+	 */
+	pub fn decode(&mut self) -> FtpResult<Option<Vec<u8>>> {
+		loop {
+			match self.buffer.iter().position(|byte| *byte == b'\n') {
+				Some(end) => {
+					let line: Vec<u8> = self.buffer.drain(..=end).collect();
+					if self.discarding {
+						self.discarding = false;
+						continue;
+					}
+					if line.len() > self.max_length {
+						return Err(FtpError::LineTooLong);
+					}
+					return Ok(Some(telnet_filter(&line).trim_ascii().to_vec()));
+				}
+				None => {
+					if self.buffer.len() > self.max_length {
+						self.buffer.clear();
+						if !self.discarding {
+							self.discarding = true;
+							return Err(FtpError::LineTooLong);
+						}
+					}
+					return Ok(None);
+				}
+			}
+		}
+	}
+}
+
+/**
+ * Remove the Telnet commands from a line. IAC IAC is a data byte 255.
+ * An IAC which is not followed by a Telnet command is kept unless it starts the line:
+ * it is what is left of IAC DM when the urgent byte has been removed from the stream.
+ */
+fn telnet_filter(line: &[u8]) -> Vec<u8> {
+	let mut result = Vec::with_capacity(line.len());
+	let mut i = 0;
+	while i < line.len() {
+		if line[i] == IAC && i + 1 < line.len() {
+			match line[i + 1] {
+				IAC => {
+					result.push(IAC);
+					i += 2;
+				}
+				WILL | WONT | DO | DONT => {
+					i += 3;
+				}
+				SE..=DONT => {
+					i += 2;
+				}
+				_ => {
+					if result.iter().all(|byte| byte.is_ascii_whitespace()) {
+						result.clear();
+					} else {
+						result.push(IAC);
+					}
+					i += 1;
+				}
+			}
+		} else {
+			result.push(line[i]);
+			i += 1;
+		}
+	}
+	result
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn decode_all(codec: &mut LineCodec) -> Vec<Vec<u8>> {
+		let mut lines = vec![];
+		while let Ok(Some(line)) = codec.decode() {
+			lines.push(line);
+		}
+		lines
+	}
+
+	#[test]
+	fn pipelined_and_split_lines() {
+		let mut codec = LineCodec::new(64);
+		codec.extend(b"NOOP\r\nPWD\r\nCW");
+		assert_eq!(decode_all(&mut codec), vec![b"NOOP".to_vec(), b"PWD".to_vec()]);
+		codec.extend(b"D /docs\r\n");
+		assert_eq!(decode_all(&mut codec), vec![b"CWD /docs".to_vec()]);
+		assert!(matches!(codec.decode(), Ok(None)));
+	}
+
+	#[test]
+	fn telnet_commands_are_removed() {
+		let mut codec = LineCodec::new(64);
+		// ABOR after IAC IP and IAC DM, then without the DM byte, read as urgent data
+		codec.extend(&[IAC, 244, IAC, 242]);
+		codec.extend(b"ABOR\r\n");
+		codec.extend(&[IAC]);
+		codec.extend(b"ABOR\r\n");
+		// Option negotiation, and IAC IAC for a data byte 255
+		codec.extend(&[IAC, WILL, 1]);
+		codec.extend(b"STOR a");
+		codec.extend(&[IAC, IAC]);
+		codec.extend(b"b\r\n");
+		assert_eq!(decode_all(&mut codec), vec![b"ABOR".to_vec(), b"ABOR".to_vec(), b"STOR a\xffb".to_vec()]);
+	}
+
+	#[test]
+	fn too_long_line_is_reported_once() {
+		let mut codec = LineCodec::new(8);
+		codec.extend(b"STOR a_long_file_name");
+		assert!(matches!(codec.decode(), Err(FtpError::LineTooLong)));
+		codec.extend(b"_and_more_of_it_again");
+		assert!(matches!(codec.decode(), Ok(None)));
+		codec.extend(b"\r\nNOOP\r\n");
+		assert_eq!(decode_all(&mut codec), vec![b"NOOP".to_vec()]);
+
+		codec.extend(b"TOO LONG LINE\r\nPWD\r\n");
+		assert!(matches!(codec.decode(), Err(FtpError::LineTooLong)));
+		assert_eq!(decode_all(&mut codec), vec![b"PWD".to_vec()]);
+	}
+
+	#[test]
+	fn clear_forgets_pending_bytes() {
+		let mut codec = LineCodec::new(64);
+		codec.extend(b"NOOP\r\nPW");
+		codec.clear();
+		codec.extend(b"D\r\n");
+		assert_eq!(decode_all(&mut codec), vec![b"D".to_vec()]);
+	}
+}
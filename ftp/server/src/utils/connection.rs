@@ -15,59 +15,184 @@ GNU General Public License for more details.
 You should have received a copy of the GNU General Public License
 along with rust-discovery.  If not, see <http://www.gnu.org/licenses/>. */
 
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
 use log::{debug, error, info};
+use std::io;
+use std::fs::File;
+use std::io::Read;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
 use std::time::Duration;
 use async_std::io as async_io;
 
-use tokio::net::tcp::{OwnedWriteHalf, OwnedReadHalf};
+use socket2::SockRef;
+use tokio::net::TcpStream;
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
 use crate::protocol::ServerResponse;
+use crate::protocol::reply::Reply;
 
+use crate::utils;
+use crate::utils::codec::LineCodec;
 use crate::utils::error::{FtpError, FtpResult};
 
-pub const TIME_OUT: u64 = 300;
 const BUFFER_SIZE: usize = 1024;
+const MAX_LINE_LENGTH: usize = 4096;
+
+/**
+ * The socket of a connection: plain TCP, or TCP protected by TLS after AUTH TLS or PROT P.
+ */
+enum Stream {
+	Plain(TcpStream),
+	Tls(Box<TlsStream<TcpStream>>),
+	Closed, // Only while the socket is upgraded or if the TLS handshake failed
+}
+
+impl AsyncRead for Stream {
+	fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+		match self.get_mut() {
+			Stream::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+			Stream::Tls(stream) => Pin::new(stream.as_mut()).poll_read(cx, buf),
+			Stream::Closed => Poll::Ready(Err(io::ErrorKind::NotConnected.into())),
+		}
+	}
+}
+
+impl AsyncWrite for Stream {
+	fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+		match self.get_mut() {
+			Stream::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+			Stream::Tls(stream) => Pin::new(stream.as_mut()).poll_write(cx, buf),
+			Stream::Closed => Poll::Ready(Err(io::ErrorKind::NotConnected.into())),
+		}
+	}
+
+	fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		match self.get_mut() {
+			Stream::Plain(stream) => Pin::new(stream).poll_flush(cx),
+			Stream::Tls(stream) => Pin::new(stream.as_mut()).poll_flush(cx),
+			Stream::Closed => Poll::Ready(Ok(())),
+		}
+	}
+
+	fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		match self.get_mut() {
+			Stream::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+			Stream::Tls(stream) => Pin::new(stream.as_mut()).poll_shutdown(cx),
+			Stream::Closed => Poll::Ready(Ok(())),
+		}
+	}
+}
 
 pub struct Connection {
 	buffer_reader: [u8; BUFFER_SIZE],
-	rx: OwnedReadHalf,
-	tx: OwnedWriteHalf,
+	codec: LineCodec,
+	stream: Stream,
+	timeout: Duration,
 }
 
 impl Connection {
-	pub fn new(rx: OwnedReadHalf, tx: OwnedWriteHalf) -> Self {
+	pub fn new(stream: TcpStream, timeout: Duration) -> Self {
 		Connection {
 			buffer_reader: [0; BUFFER_SIZE],
-			rx,
-			tx,
+			codec: LineCodec::new(MAX_LINE_LENGTH),
+			stream: Stream::Plain(stream),
+			timeout,
 		}
 	}
 
-	pub async fn read(&mut self) -> Option<String> {
-		debug!("connection::read");
+	/**
+	 * Run the server side of the TLS handshake, then use TLS for all the following exchanges.
+	 */
+	pub async fn upgrade(&mut self, acceptor: &TlsAcceptor) -> FtpResult<()> {
+		debug!("connection::upgrade");
+		// Commands sent in plain text before the handshake must not be run once the connection is protected
+		self.codec.clear();
+		match std::mem::replace(&mut self.stream, Stream::Closed) {
+			Stream::Plain(stream) => {
+				match async_io::timeout(self.timeout, acceptor.accept(stream)).await {
+					Ok(stream) => {
+						info!("TLS handshake done");
+						self.stream = Stream::Tls(Box::new(stream));
+						Ok(())
+					}
+					Err(e) => {
+						error!("TLS handshake failed: {:?}", e);
+						Err(FtpError::TlsError)
+					}
+				}
+			}
+			stream => {
+				self.stream = stream;
+				error!("Connection already protected");
+				Err(FtpError::TlsError)
+			}
+		}
+	}
+
+	fn tcp_stream(&self) -> Option<&TcpStream> {
+		match &self.stream {
+			Stream::Plain(stream) => Some(stream),
+			Stream::Tls(stream) => Some(stream.get_ref().0),
+			Stream::Closed => None,
+		}
+	}
+
+	pub fn local_addr(&self) -> io::Result<SocketAddr> {
+		self.tcp_stream().ok_or(io::ErrorKind::NotConnected)?.local_addr()
+	}
+
+	pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+		self.tcp_stream().ok_or(io::ErrorKind::NotConnected)?.peer_addr()
+	}
+
+	pub fn is_secure(&self) -> bool {
+		matches!(self.stream, Stream::Tls(_))
+	}
 
-		let mut message: String = String::new();
+	/**
+	 * Read a command line. The line is kept as bytes: file names are not always valid UTF-8.
+	 * The bytes received after the end of the line are kept for the next call.
+	 */
+	pub async fn read(&mut self) -> Option<Vec<u8>> {
+		self.read_line(Some(self.timeout)).await
+	}
+
+	/**
+	 * Read a command line without the idle timeout, while the session is busy with a transfer
+	 */
+	pub async fn read_untimed(&mut self) -> Option<Vec<u8>> {
+		self.read_line(None).await
+	}
+
+	async fn read_line(&mut self, timeout: Option<Duration>) -> Option<Vec<u8>> {
+		debug!("connection::read");
 
 		loop {
-			match async_io::timeout(Duration::from_secs(TIME_OUT), async {
-				self.buffer_reader = [0; BUFFER_SIZE];
-				self.rx.read(&mut self.buffer_reader).await
-			}).await {
+			match self.codec.decode() {
+				Ok(Some(message)) => {
+					info!(" <<<< {}", utils::loggable_command(&message));
+					return Some(message);
+				}
+				Ok(None) => {}
+				Err(e) => {
+					error!("Read: {}", e);
+					self.sendResponse(ServerResponse::UnknownCommand, "Command line too long").await.ok()?;
+					continue;
+				}
+			}
+
+			let read = self.stream.read(&mut self.buffer_reader);
+			let result = match timeout {
+				Some(timeout) => async_io::timeout(timeout, read).await,
+				None => read.await,
+			};
+			match result {
 				Ok(n) => {
 					if n > 0 {
-						match String::from_utf8(self.buffer_reader[..n].to_vec()) {
-							Ok(msg) => {
-								message.push_str(msg.trim());
-								info!(" <<<< {}", message);
-								if n < BUFFER_SIZE {
-									return Some(message);
-								}
-							}
-							Err(e) => {
-								error!("UTF_8 error, {:?}", e);
-								return None;
-							}
-						}
+						self.codec.extend(&self.buffer_reader[..n]);
+						self.read_pending();
 					} else {
 						error!("Read: Client disconnected");
 						return None;
@@ -81,19 +206,117 @@ impl Connection {
 		}
 	}
 
-	pub async fn write(&mut self, mut msg: String) -> FtpResult<()> {
+	/**
+	 * Read the bytes already received. A read stops at the urgent mark of a TCP stream (ABOR sent as urgent data),
+	 * the following bytes must be read now: tokio saw a short read and waits for a readiness event which will not come.
+	 * So the socket is read directly, without the readiness tracked by tokio.
+	 */
+	fn read_pending(&mut self) {
+		if let Stream::Plain(stream) = &self.stream {
+			let socket = SockRef::from(stream);
+			while let Ok(n) = (&*socket).read(&mut self.buffer_reader) {
+				if n == 0 {
+					break;
+				}
+				self.codec.extend(&self.buffer_reader[..n]);
+			}
+		}
+	}
+
+	/**
+	 * Read raw bytes, used by the data connection. Returns 0 at the end of the stream.
+	 */
+	pub async fn read_bytes(&mut self, buffer: &mut [u8]) -> FtpResult<usize> {
+		match async_io::timeout(self.timeout, self.stream.read(buffer)).await {
+			Ok(n) => Ok(n),
+			Err(e) => {
+				error!("Failed to receive data: {:?}", e);
+				Err(FtpError::DataConnectionError)
+			}
+		}
+	}
+
+	pub async fn write(&mut self, msg: String) -> FtpResult<()> {
+		self.write_line(msg.as_bytes()).await
+	}
+
+	/**
+	 * Write a reply line given as bytes, used when the reply contains a file name which is not valid UTF-8.
+	 */
+	pub async fn write_line(&mut self, msg: &[u8]) -> FtpResult<()> {
 		debug!("connection::write");
-		match async_io::timeout(Duration::from_secs(TIME_OUT), async {
-			msg.push_str("\r\n");
-			self.tx.write(msg.as_bytes()).await
+		let msg = [msg, b"\r\n"].concat();
+		match async_io::timeout(self.timeout, async {
+			self.stream.write_all(msg.as_slice()).await?;
+			// A TLS stream keeps the data in its buffer until it is flushed.
+			self.stream.flush().await
 		}).await {
 			Ok(_) => {
-				info!(" >>>> {}", msg);
-				return Ok(());
+				info!(" >>>> {}", String::from_utf8_lossy(&msg));
+				Ok(())
+			}
+			Err(e) => {
+				error!("Failed to send message: {}, {:?}", String::from_utf8_lossy(&msg), e);
+				Err(FtpError::SocketWriteError)
+			}
+		}
+	}
+
+	/**
+	 * Write raw bytes, used by the data connection.
+	 */
+	pub async fn write_bytes(&mut self, data: &[u8]) -> FtpResult<()> {
+		match async_io::timeout(self.timeout, self.stream.write_all(data)).await {
+			Ok(_) => Ok(()),
+			Err(e) => {
+				error!("Failed to send data: {:?}", e);
+				Err(FtpError::SocketWriteError)
+			}
+		}
+	}
+
+	/**
+	 * Send at most count bytes of a file from offset with sendfile(2): the kernel copies the file straight into the
+	 * socket, without going through a buffer of the server. Only a plain TCP connection can do it.
+	 * sendfile reads the disk when the file is not in the page cache, so it runs on a blocking thread
+	 * with a duplicate of the socket descriptor, as the reads of tokio::fs do.
+	 * Returns the number of bytes sent, 0 at the end of the file.
+	 */
+	#[cfg(target_os = "linux")]
+	pub async fn send_file(&self, file: &File, offset: u64, count: usize) -> FtpResult<usize> {
+		use std::os::fd::AsFd;
+
+		let stream = match &self.stream {
+			Stream::Plain(stream) => stream,
+			_ => return Err(FtpError::DataConnectionError),
+		};
+		let socket = stream.as_fd().try_clone_to_owned()?;
+		let file = file.try_clone()?;
+		let timeout = self.timeout;
+		match tokio::task::spawn_blocking(move || send_file_blocking(&socket, &file, offset, count, timeout)).await {
+			Ok(Ok(n)) => Ok(n),
+			Ok(Err(e)) => {
+				error!("Failed to send file: {:?}", e);
+				Err(FtpError::SocketWriteError)
 			}
 			Err(e) => {
-				error!("Failed to send message: {}, {:?}", msg, e);
-				return Err(FtpError::SocketWriteError);
+				error!("Failed to send file: {:?}", e);
+				Err(FtpError::SocketWriteError)
+			}
+		}
+	}
+
+	#[cfg(not(target_os = "linux"))]
+	pub async fn send_file(&self, _file: &File, _offset: u64, _count: usize) -> FtpResult<usize> {
+		Err(FtpError::DataConnectionError)
+	}
+
+	pub async fn flush(&mut self) -> FtpResult<()> {
+		match self.stream.flush().await {
+			Ok(_) => Ok(()),
+			Err(e) => {
+				error!("Failed to flush data: {:?}", e);
+				Err(FtpError::SocketWriteError)
 			}
 		}
 	}
@@ -103,13 +326,53 @@ impl Connection {
 		self.write(message).await
 	}
 
+	pub async fn send_reply(&mut self, reply: Reply) -> FtpResult<()> {
+		self.write_line(reply.to_bytes().as_slice()).await
+	}
+
 	pub async fn close(&mut self) {
 		debug!("connection::close");
 
-		if self.tx.shutdown().await.is_ok() {
+		if self.stream.shutdown().await.is_ok() {
 			info!("Connection closed by server");
 		} else {
 			error!("Error while closing socket");
 		}
 	}
-}
\ No newline at end of file
+}
+
+/**
+ * The sendfile(2) loop of Connection::send_file. The socket stays in non-blocking mode, tokio still owns it:
+ * when its buffer is full, poll(2) waits for room, at most timeout.
+ */
+#[cfg(target_os = "linux")]
+fn send_file_blocking(socket: &std::os::fd::OwnedFd, file: &File, offset: u64, count: usize, timeout: Duration) -> io::Result<usize> {
+	use std::os::fd::AsRawFd;
+
+	let mut position = offset as libc::off_t;
+	let timeout = timeout.as_millis().min(libc::c_int::MAX as u128) as libc::c_int;
+	loop {
+		let n = unsafe { libc::sendfile(socket.as_raw_fd(), file.as_raw_fd(), &mut position, count) };
+		if n >= 0 {
+			return Ok(n as usize);
+		}
+		let error = io::Error::last_os_error();
+		match error.kind() {
+			io::ErrorKind::WouldBlock => {
+				let mut pollfd = libc::pollfd { fd: socket.as_raw_fd(), events: libc::POLLOUT, revents: 0 };
+				match unsafe { libc::poll(&mut pollfd, 1, timeout) } {
+					0 => return Err(io::ErrorKind::TimedOut.into()),
+					n if n < 0 => {
+						let error = io::Error::last_os_error();
+						if error.kind() != io::ErrorKind::Interrupted {
+							return Err(error);
+						}
+					}
+					_ => {}
+				}
+			}
+			io::ErrorKind::Interrupted => {}
+			_ => return Err(error),
+		}
+	}
+}
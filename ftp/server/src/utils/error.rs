@@ -19,7 +19,6 @@ use std::fmt::{Debug, Display, Formatter};
 use std::result;
 use std::str::Utf8Error;
 use std::error;
-use log::debug;
 
 use tokio::io;
 
@@ -27,7 +26,12 @@ pub enum FtpError {
 	SocketWriteError, // Writ socket error
 	FileSystemError,
 	DataConnectionError, // Error with data connection
+	TlsError, // TLS handshake failed
+	LineTooLong, // Command line longer than the maximum length
+	QuotaExceeded, // Upload beyond the quota of the user
+	StorageFull, // No space left on the device
 	Abord(String), // Stop current data transfer
+	InternalError(String), // Any other error, with its description
 }
 
 pub type FtpResult<T> = result::Result<T, FtpError>;
@@ -38,7 +42,12 @@ impl Display for FtpError {
 			FtpError::SocketWriteError => { write!(f, "!!Error!! Connection closed") }
 			FtpError::DataConnectionError => { write!(f, "!!Error!! Data connection error") }
 			FtpError::FileSystemError => { write!(f, "!!Error!! File system error") }
+			FtpError::TlsError => { write!(f, "!!Error!! TLS error") }
+			FtpError::LineTooLong => { write!(f, "!!Error!! Command line too long") }
+			FtpError::QuotaExceeded => { write!(f, "!!Error!! Quota exceeded") }
+			FtpError::StorageFull => { write!(f, "!!Error!! No space left on device") }
 			FtpError::Abord(msg) => { write!(f, "!!Error!! Stop current data transfer: {}", msg) }
+			FtpError::InternalError(msg) => { write!(f, "!!Error!! {}", msg) }
 		}
 	}
 }
@@ -53,6 +62,11 @@ impl Debug for FtpError {
 
 impl From<io::Error> for FtpError {
 	fn from(error: io::Error) -> Self {
+		match error.kind() {
+			io::ErrorKind::QuotaExceeded => return FtpError::QuotaExceeded,
+			io::ErrorKind::StorageFull => return FtpError::StorageFull,
+			_ => {}
+		}
 		format!("Input / Output error : {:?}", error).into()
 	}
 }
@@ -71,6 +85,6 @@ impl From<Utf8Error> for FtpError {
 
 impl From<String> for FtpError {
 	fn from(error: String) -> Self {
-		error.into()
+		FtpError::InternalError(error)
 	}
 }
\ No newline at end of file
@@ -0,0 +1,191 @@
+/* Copyright 2022 Pierrick MARIE
+
+This file is part of rust-discovery
+
+LCS is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+Rust-discovery is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with rust-discovery.  If not, see <http://www.gnu.org/licenses/>. */
+
+use std::ffi::OsStr;
+use std::path::{Component, Path, PathBuf};
+
+use log::{debug, error};
+
+/**
+ * Keep a session inside its root directory.
+ *
+ * The client only sees virtual paths: "/" is the root directory of the user.
+ * A virtual path is translated into a real path on disk only if it stays inside the root directory,
+ * including when symbolic links are followed.
+ */
+pub struct Jail {
+	root: PathBuf,
+}
+
+impl Jail {
+	pub fn new(root: &Path) -> std::io::Result<Self> {
+		Ok(Jail {
+			root: root.canonicalize()?,
+		})
+	}
+
+	/**
+	 * Build the virtual absolute path of `arg` sent by a client whose current directory is `current_directory`.
+	 * Returns None if the path goes up above the root directory.
+	 */
+	pub fn normalize(current_directory: &Path, arg: &Path) -> Option<PathBuf> {
+		let mut path = if arg.has_root() {
+			PathBuf::from("/")
+		} else {
+			current_directory.to_path_buf()
+		};
+
+		for (i, component) in arg.components().enumerate() {
+			match component {
+				Component::Normal(name) if i == 0 && name == OsStr::new("~") => {
+					path = PathBuf::from("/");
+				}
+				Component::Normal(name) => path.push(name),
+				Component::ParentDir => {
+					if !path.pop() {
+						error!("Path escapes the root directory: {:?}", arg);
+						return None;
+					}
+				}
+				Component::RootDir | Component::CurDir => {}
+				Component::Prefix(_) => return None,
+			}
+		}
+		Some(path)
+	}
+
+	/**
+	 * Translate a virtual path given by `normalize` into a path on disk, the path of a file itself:
+	 * if the last component is a symbolic link, the path names the link and not its target (DELE, RNFR, RNTO, STOR).
+	 * Returns None if the directory of the file resolves outside the root directory.
+	 */
+	pub fn real_path(&self, virtual_path: &Path) -> Option<PathBuf> {
+		debug!("Jail::real_path {:?}", virtual_path);
+		match virtual_path.file_name() {
+			Some(name) => Some(self.resolved_path(virtual_path.parent()?)?.join(name)),
+			None => self.resolved_path(virtual_path),
+		}
+	}
+
+	/**
+	 * Same as `real_path`, but a symbolic link at the end of the path is followed too:
+	 * for the commands reading through the link (RETR, LIST, CWD, SIZE...).
+	 * Returns None if the deepest existing part of the path resolves outside the root directory.
+	 */
+	pub fn resolved_path(&self, virtual_path: &Path) -> Option<PathBuf> {
+		let path = self.root.join(virtual_path.strip_prefix("/").ok()?);
+
+		// Look for the deepest part of the path that exists, the end of the path may be created later (STOR, MKD).
+		let mut existing = path.as_path();
+		let mut missing = vec![];
+		while existing.symlink_metadata().is_err() {
+			missing.push(existing.file_name()?);
+			existing = existing.parent()?;
+		}
+
+		// A dangling symbolic link cannot be canonicalized: it is rejected too.
+		let mut real_path = existing.canonicalize().ok()?;
+		if !real_path.starts_with(&self.root) {
+			error!("Path escapes the root directory: {:?} -> {:?}", virtual_path, real_path);
+			return None;
+		}
+		for name in missing.iter().rev() {
+			real_path.push(name);
+		}
+		Some(real_path)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::fs;
+	use std::os::unix::fs::symlink;
+
+	#[test]
+	fn normalize_relative_and_absolute_paths() {
+		let cwd = Path::new("/docs");
+		assert_eq!(Jail::normalize(cwd, Path::new("a.txt")), Some(PathBuf::from("/docs/a.txt")));
+		assert_eq!(Jail::normalize(cwd, Path::new("/a.txt")), Some(PathBuf::from("/a.txt")));
+		assert_eq!(Jail::normalize(cwd, Path::new("./sub/../b.txt")), Some(PathBuf::from("/docs/b.txt")));
+		assert_eq!(Jail::normalize(cwd, Path::new("~/c.txt")), Some(PathBuf::from("/c.txt")));
+	}
+
+	#[test]
+	fn normalize_refuses_to_go_above_the_root() {
+		let cwd = Path::new("/docs");
+		assert_eq!(Jail::normalize(cwd, Path::new("..")), Some(PathBuf::from("/")));
+		assert_eq!(Jail::normalize(cwd, Path::new("../..")), None);
+		assert_eq!(Jail::normalize(cwd, Path::new("/../etc/passwd")), None);
+	}
+
+	#[test]
+	fn real_path_inside_the_root() {
+		let root = tempfile::tempdir().unwrap();
+		fs::create_dir(root.path().join("docs")).unwrap();
+		let jail = Jail::new(root.path()).unwrap();
+		let real_root = root.path().canonicalize().unwrap();
+		assert_eq!(jail.real_path(Path::new("/")), Some(real_root.clone()));
+		assert_eq!(jail.real_path(Path::new("/docs")), Some(real_root.join("docs")));
+		// Files still to be created
+		assert_eq!(jail.real_path(Path::new("/docs/new/file.txt")), Some(real_root.join("docs/new/file.txt")));
+	}
+
+	#[test]
+	fn resolved_path_refuses_links_out_of_the_root() {
+		let outside = tempfile::tempdir().unwrap();
+		let root = tempfile::tempdir().unwrap();
+		symlink(outside.path(), root.path().join("out")).unwrap();
+		symlink("docs", root.path().join("in")).unwrap();
+		symlink("missing", root.path().join("dangling")).unwrap();
+		fs::create_dir(root.path().join("docs")).unwrap();
+		let jail = Jail::new(root.path()).unwrap();
+		let real_root = root.path().canonicalize().unwrap();
+		assert_eq!(jail.resolved_path(Path::new("/out")), None);
+		assert_eq!(jail.resolved_path(Path::new("/out/new.txt")), None);
+		assert_eq!(jail.real_path(Path::new("/out/new.txt")), None);
+		assert_eq!(jail.resolved_path(Path::new("/dangling")), None);
+		assert_eq!(jail.resolved_path(Path::new("/in/a.txt")), Some(real_root.join("docs/a.txt")));
+		assert_eq!(jail.real_path(Path::new("/in/a.txt")), Some(real_root.join("docs/a.txt")));
+	}
+
+	#[test]
+	fn real_path_names_the_link_itself() {
+		let outside = tempfile::tempdir().unwrap();
+		let root = tempfile::tempdir().unwrap();
+		fs::write(root.path().join("a.txt"), "a").unwrap();
+		symlink("a.txt", root.path().join("link.txt")).unwrap();
+		symlink(outside.path(), root.path().join("out")).unwrap();
+		let jail = Jail::new(root.path()).unwrap();
+		let real_root = root.path().canonicalize().unwrap();
+		assert_eq!(jail.real_path(Path::new("/link.txt")), Some(real_root.join("link.txt")));
+		assert_eq!(jail.resolved_path(Path::new("/link.txt")), Some(real_root.join("a.txt")));
+		// A link going out of the root can still be removed or renamed
+		assert_eq!(jail.real_path(Path::new("/out")), Some(real_root.join("out")));
+	}
+
+	#[test]
+	fn deleting_a_link_keeps_its_target() {
+		let root = tempfile::tempdir().unwrap();
+		fs::write(root.path().join("a.txt"), "a").unwrap();
+		symlink("a.txt", root.path().join("link.txt")).unwrap();
+		let jail = Jail::new(root.path()).unwrap();
+		fs::remove_file(jail.real_path(Path::new("/link.txt")).unwrap()).unwrap();
+		assert!(root.path().join("link.txt").symlink_metadata().is_err());
+		assert_eq!(fs::read_to_string(root.path().join("a.txt")).unwrap(), "a");
+	}
+}
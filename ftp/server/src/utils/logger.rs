@@ -15,13 +15,13 @@ GNU General Public License for more details.
 You should have received a copy of the GNU General Public License
 along with rust-discovery.  If not, see <http://www.gnu.org/licenses/>. */
 
-use log::{Record, Metadata, SetLoggerError};
+use log::{LevelFilter, Record, Metadata, SetLoggerError};
 
 struct SimpleLogger;
 
 impl log::Log for SimpleLogger {
 	fn enabled(&self, metadata: &Metadata) -> bool {
-		metadata.level() <= crate::LEVEL
+		metadata.level() <= log::max_level()
 	}
 	
 	fn log(&self, record: &Record) {
@@ -35,7 +35,7 @@ impl log::Log for SimpleLogger {
 
 static LOGGER: SimpleLogger = SimpleLogger;
 
-pub fn init() -> Result<(), SetLoggerError> {
+pub fn init(level: LevelFilter) -> Result<(), SetLoggerError> {
 	log::set_logger(&LOGGER)
-		.map(|()| log::set_max_level(crate::LEVEL.to_level_filter()))
+		.map(|()| log::set_max_level(level))
 }
\ No newline at end of file
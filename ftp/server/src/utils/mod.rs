@@ -15,183 +15,247 @@ GNU General Public License for more details.
 You should have received a copy of the GNU General Public License
 along with rust-discovery.  If not, see <http://www.gnu.org/licenses/>. */
 
-use std::fs;
-use std::fs::File;
-use std::io::Read;
-use std::net::{IpAddr, SocketAddr};
-use std::os::unix::fs::{MetadataExt, PermissionsExt};
-use std::path::{Path, PathBuf};
+use std::ffi::{OsStr, OsString};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::str::FromStr;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::Path;
 
 use chrono::{DateTime, Utc};
-use log::{debug, error};
+use log::debug;
 use regex::Regex;
-use crate::ADDR;
 
+/**
+ * Time format of the modify fact and of MDTM replies (RFC 3659), always in UTC
+ */
+pub const MACHINE_TIME_FORMAT: &str = "%Y%m%d%H%M%S";
+
+pub mod ascii;
+pub mod codec;
 pub mod connection;
 pub mod error;
+pub mod jail;
 pub mod logger;
+pub mod tls;
 
+use crate::storage::{Metadata, Storage};
 use crate::utils::error::{FtpError, FtpResult};
 
-pub fn get_absolut_path(arg: &PathBuf, current_directory: &PathBuf) -> Option<PathBuf> {
-	if let Some(p) = arg.to_str() { // Path exists
-		let mut path: String = p.to_string();
-		if !path.starts_with('/') { // This is a relative path
-			if path.starts_with("./") || path.starts_with("~/") {
-				path.remove(0); // removing the first char (.)
-				path.remove(0); // removing the new first char (/)
-			}
-			path = format!("{}/{}", current_directory.to_str().unwrap(), path);
-		}
-		if path.ends_with('/') {
-			path.pop();
-		}
-		return Some(PathBuf::from(path));
+/**
+ * A command line as it can be logged: the password sent with PASS is hidden
+ */
+pub fn loggable_command(line: &[u8]) -> String {
+	match line.get(..5) {
+		Some(start) if start.eq_ignore_ascii_case(b"PASS ") => "PASS xxxx".to_string(),
+		_ => String::from_utf8_lossy(line).into_owned(),
 	}
-	None
 }
 
+/**
+ * Parse the argument of PORT: h1,h2,h3,h4,p1,p2 where each field is a byte.
+ * Returns None if a field is not a number between 0 and 255.
+ */
 pub fn parse_port(msg: String) -> Option<(IpAddr, u16)> {
 	debug!("client::parse_port {}", msg);
 	let re = Regex::new(r"^([[:digit:]]{1,3}),([[:digit:]]{1,3}),([[:digit:]]{1,3}),([[:digit:]]{1,3}),([[:digit:]]{1,3}),([[:digit:]]{1,3})$").ok()?;
 	let cap = re.captures(msg.as_str())?;
 
-	let mut addr: [u8; 4] = [0; 4];
-	for i in 1..5 {
-		addr[i - 1] = cap.get(i).unwrap().as_str().to_string().parse::<u8>().ok()?;
+	let mut fields: [u8; 6] = [0; 6];
+	for (i, field) in fields.iter_mut().enumerate() {
+		*field = cap.get(i + 1)?.as_str().parse::<u8>().ok()?;
 	}
 
-	let port1 = cap.get(5).unwrap().as_str().to_string().parse::<u16>().ok()?;
-	let port2 = cap.get(6).unwrap().as_str().to_string().parse::<u16>().ok()?;
-	let port = port1 * 256 + port2;
-
+	let addr = [fields[0], fields[1], fields[2], fields[3]];
+	let port = u16::from(fields[4]) << 8 | u16::from(fields[5]);
 	Some((IpAddr::from(addr), port))
 }
 
-pub fn get_addr_msg(addr: SocketAddr) -> String {
-	let ip = ADDR.replace(".", ",");
-	let port = addr.port();
+/**
+ * Parse the argument of EPRT (RFC 2428): |1|132.235.1.2|6275| or |2|1080::8:800:200C:417A|5282|
+ * Returns None if the argument is malformed, Some(None) if the network protocol is not supported.
+ */
+pub fn parse_eprt(msg: &str) -> Option<Option<SocketAddr>> {
+	debug!("client::parse_eprt {}", msg);
+	let delimiter = msg.chars().next()?;
+	let fields: Vec<&str> = msg.split(delimiter).collect();
+	if fields.len() != 5 || !fields[0].is_empty() || !fields[4].is_empty() {
+		return None;
+	}
+
+	if fields[1] != "1" && fields[1] != "2" {
+		return Some(None);
+	}
+
+	let port = fields[3].parse::<u16>().ok()?;
+	match (fields[1], IpAddr::from_str(fields[2]).ok()?) {
+		("1", ip @ IpAddr::V4(_)) | ("2", ip @ IpAddr::V6(_)) => Some(Some(SocketAddr::new(ip, port))),
+		_ => None,
+	}
+}
+
+/**
+ * Format the argument of the PASV reply: (h1,h2,h3,h4,p1,p2)
+ */
+pub fn get_addr_msg(ip: Ipv4Addr, port: u16) -> String {
+	let ip = ip.to_string().replace('.', ",");
 	let port1 = port / 256;
 	let port2 = port % 256;
 
 	format!("({},{},{})", ip, port1, port2)
 }
 
-pub fn get_file(path: &Path) -> Option<Vec<u8>> {
-	let mut result: Vec<u8> = vec![];
+/**
+ * Names of the files of a directory prefixed by the virtual path of the directory
+ */
+pub fn get_nls(storage: &dyn Storage, path: &Path) -> Vec<Vec<u8>> {
+	let mut files_info = vec![];
 
-	match File::open(path) {
-		Ok(mut file) => {
-			match file.read_to_end(&mut result) {
-				Ok(_) => {}
-				Err(e) => {
-					error!("Failed to read file: {}", e);
-					return None;
-				}
-			};
-		}
-		Err(e) => {
-			error!("Failed to open file {:?}: {}", path, e);
-			return None;
+	match storage.metadata(path) {
+		Ok(metadata) if metadata.is_dir => {
+			for (filename, _) in get_entries(storage, path) {
+				files_info.push(path.join(filename).into_os_string().into_vec());
+			}
 		}
+		Ok(_) => files_info.push(path.as_os_str().as_bytes().to_vec()),
+		Err(_) => {}
+	}
+
+	files_info
+}
+
+fn get_file_info(metadata: &Metadata) -> String {
+	let mut octal_right = format!("{:o}", metadata.mode);
+	octal_right = octal_right[octal_right.len() - 3..octal_right.len()].to_string();
+	let mut right = "".to_string();
+	for c in octal_right.chars() {
+		right += octal_to_string(c);
 	}
-	Some(result)
+
+	let is_dir = if metadata.is_dir { 'd' } else { '-' };
+
+	let modification: DateTime<Utc> = DateTime::from(metadata.modified);
+
+	format!("{}{} {} {} {}      {}",
+			is_dir,
+			right,
+			metadata.uid,
+			metadata.gid,
+			metadata.len,
+			modification.format("%Y %b %d %H:%M"))
 }
 
-pub fn get_nls(working_path: &Path, prefix: &str) -> Vec<String> {
-	let mut files_info = vec![];
-	let mut filename; //  = path.as_ref().unwrap().file_name().to_str().unwrap().to_string();
-
-	if working_path.is_dir() {
-		if let Ok(paths) = fs::read_dir(working_path) {
-			for path in paths {
-				filename = path.as_ref().unwrap().file_name().to_str().unwrap().to_string();
-
-				if filename.chars().next().unwrap() != '.' {
-					let msg;
-					if prefix.is_empty() {
-						msg = filename;
-					} else {
-						if prefix.ends_with('/') {
-							msg = format!("{}{}", prefix, filename)
-						} else {
-							msg = format!("{}/{}", prefix, filename)
-						}
+/**
+ * Walk the entries to list for the given path: the visible files of a directory or the file itself
+ * File names are kept as they are on the file system, they are not always valid UTF-8.
+ */
+fn get_entries(storage: &dyn Storage, path: &Path) -> Vec<(OsString, Metadata)> {
+	let mut entries = vec![];
+
+	match storage.metadata(path) {
+		Ok(metadata) if metadata.is_dir => {
+			if let Ok(list) = storage.list(path) {
+				for entry in list {
+					if !is_hidden(&entry.name) {
+						entries.push((entry.name, entry.metadata));
 					}
-
-					files_info.push(msg);
 				}
 			}
 		}
-	} else {
-		files_info.push(working_path.to_str().unwrap().to_string());
+		Ok(metadata) => {
+			if let Some(filename) = path.file_name() {
+				if metadata.is_file && !is_hidden(filename) {
+					entries.push((filename.to_os_string(), metadata));
+				}
+			}
+		}
+		Err(_) => {}
 	}
 
-	files_info
+	entries
 }
 
-fn get_file_info(path: &Path) -> FtpResult<String> {
-	if let Ok(file) = File::open(path) {
-		let metadata = file.metadata().unwrap();
-		let mode = metadata.permissions().mode();
-		let mut octal_right = format!("{:o}", mode);
-		octal_right = octal_right[octal_right.len() - 3..octal_right.len()].to_string();
-		let is_dir;
-
-		let mut right = "".to_string();
-		for c in octal_right.chars() {
-			right += octal_to_string(c);
-		}
+fn is_hidden(filename: &OsStr) -> bool {
+	filename.as_bytes().first() == Some(&b'.')
+}
 
-		if metadata.is_dir() {
-			is_dir = 'd';
-		} else {
-			is_dir = '-';
-		}
+/**
+ * A listing line: the information about a file followed by its name
+ */
+fn listing_line(info: &str, filename: &OsStr) -> Vec<u8> {
+	[info.as_bytes(), b" ", filename.as_bytes()].concat()
+}
 
-		let modification: DateTime<Utc> = DateTime::from(metadata.modified().unwrap());
+pub fn get_ls(storage: &dyn Storage, path: &Path) -> Vec<Vec<u8>> {
+	let mut files_info = vec![];
 
-		return Ok(format!("{}{} {} {} {}      {}",
-					is_dir,
-					right,
-					metadata.uid(),
-					metadata.gid(),
-					metadata.size(),
-					modification.format("%Y %b %d %H:%M")));
+	for (filename, metadata) in get_entries(storage, path) {
+		files_info.push(listing_line(get_file_info(&metadata).as_str(), &filename));
 	}
-	return Err(FtpError::FileSystemError);
+
+	files_info
 }
 
-pub fn get_ls(path: &Path) -> Vec<String> {
-	let mut files_info = vec![];
+/**
+ * RFC 3659 facts of a file: type, size, modify, perm and unique
+ * The owner permission bits are used to build the perm fact, a read-only account never gets the write permissions.
+ */
+fn get_facts(metadata: &Metadata, read_only: bool, current_dir: bool) -> String {
+	let readable = metadata.mode & 0o400 != 0;
+	let writable = metadata.mode & 0o200 != 0 && !read_only;
+	let executable = metadata.mode & 0o100 != 0;
+
+	let mut perm = "".to_string();
+	let kind;
+	if metadata.is_dir {
+		kind = if current_dir { "cdir" } else { "dir" };
+		if readable && executable {
+			perm.push_str("el");
+		}
+		if writable && executable {
+			perm.push_str("cmdfp");
+		}
+	} else {
+		kind = "file";
+		if readable {
+			perm.push('r');
+		}
+		if writable {
+			perm.push_str("awdf");
+		}
+	}
 
-	let mut filename; //  = path.as_ref().unwrap().file_name().to_str().unwrap().to_string();
+	let modification: DateTime<Utc> = DateTime::from(metadata.modified);
 
+	format!("type={};size={};modify={};perm={};unique={:x}U{:x};",
+			kind,
+			metadata.len,
+			modification.format(MACHINE_TIME_FORMAT),
+			perm,
+			metadata.device,
+			metadata.inode)
+}
 
-	if path.exists() {
-		if path.is_dir() {
-			if let Ok(paths) = fs::read_dir(path) {
-				for path in paths {
-					filename = path.as_ref().unwrap().file_name().to_str().unwrap().to_string();
+/**
+ * One MLST fact line for the given file, without its name
+ */
+pub fn get_mlst(storage: &dyn Storage, path: &Path, read_only: bool) -> FtpResult<String> {
+	match storage.metadata(path) {
+		Ok(metadata) => Ok(get_facts(&metadata, read_only, false)),
+		Err(_) => Err(FtpError::FileSystemError),
+	}
+}
 
-					if filename.chars().next().unwrap() != '.' {
-						if let Ok(msg) = get_file_info(path.as_ref().unwrap().path().as_path()) {
-							files_info.push(format!("{} {}", msg, filename));
-						}
-					}
-				}
-			}
-		} else {
-			if path.is_file() {
-				filename = path.file_name().unwrap().to_str().unwrap().to_string();
+/**
+ * MLSD lines for a directory: the directory itself then each entry
+ */
+pub fn get_mlsd(storage: &dyn Storage, path: &Path, read_only: bool) -> Vec<Vec<u8>> {
+	let mut files_info = vec![];
 
-				if filename.chars().next().unwrap() != '.' {
-					if let Ok(msg) = get_file_info(path) {
-						files_info.push(format!("{} {}", msg, filename));
-					}
-				}
-			}
-		}
+	if let Ok(metadata) = storage.metadata(path) {
+		files_info.push(listing_line(get_facts(&metadata, read_only, true).as_str(), OsStr::new(".")));
+	}
+	for (filename, metadata) in get_entries(storage, path) {
+		files_info.push(listing_line(get_facts(&metadata, read_only, false).as_str(), &filename));
 	}
 
 	files_info
@@ -210,3 +274,42 @@ fn octal_to_string(octal_right: char) -> &'static str {
 		_ => { "" }
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn loggable_command_hides_passwords() {
+		assert_eq!(loggable_command(b"PASS secret"), "PASS xxxx");
+		assert_eq!(loggable_command(b"pass secret"), "PASS xxxx");
+		assert_eq!(loggable_command(b"USER alice"), "USER alice");
+		assert_eq!(loggable_command(b"PASSWORD"), "PASSWORD");
+	}
+
+	#[test]
+	fn parse_port_reads_address_and_port() {
+		let (ip, port) = parse_port("192,168,1,2,4,1".to_string()).unwrap();
+		assert_eq!(ip, IpAddr::from([192, 168, 1, 2]));
+		assert_eq!(port, 4 * 256 + 1);
+		assert_eq!(parse_port("127,0,0,1,255,255".to_string()).unwrap().1, u16::MAX);
+	}
+
+	#[test]
+	fn parse_port_rejects_fields_above_255() {
+		assert!(parse_port("127,0,0,1,999,1".to_string()).is_none());
+		assert!(parse_port("127,0,0,1,1,256".to_string()).is_none());
+		assert!(parse_port("256,0,0,1,1,1".to_string()).is_none());
+		assert!(parse_port("127,0,0,1,1".to_string()).is_none());
+		assert!(parse_port("127,0,0,1,+1,1".to_string()).is_none());
+	}
+
+	#[test]
+	fn parse_eprt_reads_both_protocols() {
+		assert_eq!(parse_eprt("|1|132.235.1.2|6275|"), Some(Some("132.235.1.2:6275".parse().unwrap())));
+		assert_eq!(parse_eprt("|2|1080::8:800:200C:417A|5282|"), Some(Some("[1080::8:800:200C:417A]:5282".parse().unwrap())));
+		assert_eq!(parse_eprt("|3|132.235.1.2|6275|"), Some(None));
+		assert_eq!(parse_eprt("|1|1080::8|5282|"), None);
+		assert_eq!(parse_eprt("|1|132.235.1.2|70000|"), None);
+	}
+}
@@ -0,0 +1,51 @@
+/* Copyright 2022 Pierrick MARIE
+
+This file is part of rust-discovery
+
+LCS is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+Rust-discovery is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with rust-discovery.  If not, see <http://www.gnu.org/licenses/>. */
+
+use std::fs::File;
+use std::io::{BufReader, Error, ErrorKind};
+use std::path::Path;
+use std::sync::Arc;
+
+use log::{debug, info};
+use rustls::ServerConfig;
+use tokio_rustls::TlsAcceptor;
+
+/**
+ * Build the TLS acceptor used by AUTH TLS and PROT P from a PEM certificate chain and its PEM private key.
+ */
+pub fn load_acceptor(certificate: &Path, private_key: &Path) -> std::io::Result<TlsAcceptor> {
+	debug!("tls::load_acceptor {:?} {:?}", certificate, private_key);
+
+	let certificates = rustls_pemfile::certs(&mut BufReader::new(File::open(certificate)?))
+		.collect::<Result<Vec<_>, _>>()?;
+	if certificates.is_empty() {
+		return Err(Error::new(ErrorKind::InvalidData, format!("No certificate found in {:?}", certificate)));
+	}
+
+	let key = rustls_pemfile::private_key(&mut BufReader::new(File::open(private_key)?))?
+		.ok_or_else(|| Error::new(ErrorKind::InvalidData, format!("No private key found in {:?}", private_key)))?;
+
+	let config = ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+		.with_safe_default_protocol_versions()
+		.map_err(|e| Error::new(ErrorKind::InvalidInput, e))?
+		.with_no_client_auth()
+		.with_single_cert(certificates, key)
+		.map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+
+	info!("TLS certificate loaded from {:?}", certificate);
+	Ok(TlsAcceptor::from(Arc::new(config)))
+}
@@ -0,0 +1,244 @@
+/* Copyright 2022 Pierrick MARIE
+
+This file is part of rust-discovery
+
+LCS is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+Rust-discovery is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with rust-discovery.  If not, see <http://www.gnu.org/licenses/>. */
+
+/*
+What the integration tests share with the benchmarks: a server started with a temporary configuration and a minimal FTP client.
+*/
+
+use std::fs;
+use std::io::{self, Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+
+pub const USER: &str = "bench";
+pub const PASSWORD: &str = "bench";
+pub const BIG_FILE: &str = "big.dat";
+
+/// The server binary of the package, running until it is dropped.
+/// Its configuration, users file and root directory are in a temporary directory, removed with it.
+pub struct Server {
+	pub port: u16,
+	#[allow(dead_code)]
+	pub content: Arc<Vec<u8>>, // Of BIG_FILE
+	child: Child,
+	directory: PathBuf,
+}
+
+impl Server {
+	/// Start a server whose root directory holds BIG_FILE, file_size bytes long.
+	/// Its bytes follow a pattern which does not repeat every power of 2, so a download from a wrong offset is detected.
+	/// config is added to the configuration file.
+	pub async fn start(name: &str, file_size: usize, config: &str) -> io::Result<Self> {
+		let directory = std::env::temp_dir().join(format!("ftp-server-{}-{}", name, std::process::id()));
+		let port = portpicker::pick_unused_port().ok_or_else(|| Error::new(ErrorKind::AddrNotAvailable, "No free port"))?;
+		let content = Arc::new((0..file_size).map(|i| (i % 251) as u8).collect::<Vec<u8>>());
+		let config = prepare(directory.as_path(), port, content.as_slice(), config)?;
+		let child = Command::new(env!("CARGO_BIN_EXE_ftp-server"))
+			.arg("--config")
+			.arg(config)
+			.stdout(Stdio::null())
+			.spawn()?;
+		let server = Server { port, content, child, directory };
+
+		for _ in 0..50 {
+			if TcpStream::connect(("127.0.0.1", port)).await.is_ok() {
+				return Ok(server);
+			}
+			tokio::time::sleep(Duration::from_millis(100)).await;
+		}
+		Err(Error::new(ErrorKind::TimedOut, "The server did not start"))
+	}
+
+	/// Root directory of the user on the disk
+	#[allow(dead_code)]
+	pub fn root(&self) -> PathBuf {
+		self.directory.join("root")
+	}
+
+	/// CPU time used by the server since it started, user and system, read from /proc (Linux)
+	#[allow(dead_code)]
+	pub fn cpu_time(&self) -> Option<Duration> {
+		let stat = fs::read_to_string(format!("/proc/{}/stat", self.child.id())).ok()?;
+		// The name of the command may contain spaces, the fields are counted after it
+		let fields: Vec<&str> = stat.rsplit_once(')')?.1.split_whitespace().collect();
+		let ticks = fields.get(11)?.parse::<u64>().ok()? + fields.get(12)?.parse::<u64>().ok()?;
+		let ticks_per_second = unsafe { libc::sysconf(libc::_SC_CLK_TCK) } as u64;
+		Some(Duration::from_millis(ticks * 1000 / ticks_per_second.max(1)))
+	}
+}
+
+impl Drop for Server {
+	fn drop(&mut self) {
+		let _ = self.child.kill();
+		let _ = self.child.wait();
+		let _ = fs::remove_dir_all(self.directory.as_path());
+	}
+}
+
+fn prepare(directory: &Path, port: u16, content: &[u8], config: &str) -> io::Result<PathBuf> {
+	let root = directory.join("root");
+	fs::create_dir_all(root.as_path())?;
+	fs::write(root.join(BIG_FILE), content)?;
+
+	let hash = bcrypt::hash(PASSWORD, 4).map_err(Error::other)?;
+	let users = directory.join("users.toml");
+	fs::write(users.as_path(), format!("[[user]]\nname = \"{}\"\npassword = \"{}\"\nroot = {:?}\n", USER, hash, root))?;
+
+	let path = directory.join("ftp-server.toml");
+	fs::write(path.as_path(), format!("listen_addresses = [\"127.0.0.1\"]\nport = {}\nlog_level = \"warn\"\nusers_file = {:?}\n{}\n",
+									  port, users, config))?;
+	Ok(path)
+}
+
+/// A control connection, with just what the benchmarks and the tests need
+pub struct Session {
+	reader: BufReader<OwnedReadHalf>,
+	writer: OwnedWriteHalf,
+}
+
+impl Session {
+	#[allow(dead_code)]
+	pub async fn connect(port: u16) -> io::Result<Self> {
+		let (reader, writer) = TcpStream::connect(("127.0.0.1", port)).await?.into_split();
+		let mut session = Session { reader: BufReader::new(reader), writer };
+		session.reply().await?;
+		Ok(session)
+	}
+
+	/// Login, then switch to Image type
+	#[allow(dead_code)]
+	pub async fn login(port: u16) -> io::Result<Self> {
+		let mut session = Session::connect(port).await?;
+		session.expect(format!("USER {}", USER).as_str(), "331").await?;
+		session.expect(format!("PASS {}", PASSWORD).as_str(), "230").await?;
+		session.expect("TYPE I", "200").await?;
+		Ok(session)
+	}
+
+	/// Read a reply, multi-line replies included, and return its last line
+	pub async fn reply(&mut self) -> io::Result<String> {
+		loop {
+			let mut line = String::new();
+			if self.reader.read_line(&mut line).await? == 0 {
+				return Err(Error::new(ErrorKind::UnexpectedEof, "Control connection closed"));
+			}
+			if line.len() > 3 && line.as_bytes()[3] == b' ' && line[..3].bytes().all(|byte| byte.is_ascii_digit()) {
+				return Ok(line.trim_end().to_string());
+			}
+		}
+	}
+
+	pub async fn command(&mut self, command: &str) -> io::Result<String> {
+		self.writer.write_all(format!("{}\r\n", command).as_bytes()).await?;
+		self.reply().await
+	}
+
+	pub async fn expect(&mut self, command: &str, code: &str) -> io::Result<()> {
+		self.writer.write_all(format!("{}\r\n", command).as_bytes()).await?;
+		self.expect_reply(command, code).await
+	}
+
+	/// Read the reply to command, an error if it has not the expected code
+	async fn expect_reply(&mut self, command: &str, code: &str) -> io::Result<()> {
+		let reply = self.reply().await?;
+		if reply.starts_with(code) {
+			Ok(())
+		} else {
+			Err(Error::other(format!("{}: unexpected reply {}", command, reply)))
+		}
+	}
+
+	/// Open a data connection with EPSV: the port is between (||| and |)
+	pub async fn epsv(&mut self) -> io::Result<TcpStream> {
+		let reply = self.command("EPSV").await?;
+		let port = reply.split("(|||").nth(1)
+			.and_then(|end| end.split('|').next())
+			.and_then(|port| port.parse::<u16>().ok())
+			.ok_or_else(|| Error::other(format!("EPSV: unexpected reply {}", reply)))?;
+		TcpStream::connect(("127.0.0.1", port)).await
+	}
+
+	/// Run a command which sends data (RETR, LIST, NLST...) and return the data
+	#[allow(dead_code)]
+	pub async fn download(&mut self, command: &str) -> io::Result<Vec<u8>> {
+		let mut data = self.epsv().await?;
+		self.expect(command, "150").await?;
+		let mut content = vec![];
+		data.read_to_end(&mut content).await?;
+		self.expect_reply(command, "226").await?;
+		Ok(content)
+	}
+
+	/// Upload a file with STOR
+	#[allow(dead_code)]
+	pub async fn stor(&mut self, name: &str, content: &[u8]) -> io::Result<()> {
+		let command = format!("STOR {}", name);
+		let mut data = self.epsv().await?;
+		self.expect(command.as_str(), "150").await?;
+		data.write_all(content).await?;
+		data.shutdown().await?;
+		drop(data);
+		self.expect_reply(command.as_str(), "226").await
+	}
+
+	/// Download a file and drop its bytes, returns the number of bytes received
+	#[allow(dead_code)]
+	pub async fn retr_discard(&mut self, name: &str) -> io::Result<u64> {
+		let command = format!("RETR {}", name);
+		let mut data = self.epsv().await?;
+		self.expect(command.as_str(), "150").await?;
+		let total = tokio::io::copy(&mut data, &mut tokio::io::sink()).await?;
+		self.expect_reply(command.as_str(), "226").await?;
+		Ok(total)
+	}
+
+	/// Download a file from offset (REST if not 0) and compare the bytes received with content, the whole file.
+	/// Returns the number of bytes received.
+	#[allow(dead_code)]
+	pub async fn retr(&mut self, name: &str, offset: u64, content: &[u8]) -> io::Result<u64> {
+		let command = format!("RETR {}", name);
+		let expected = content.get(offset as usize..).unwrap_or_default();
+		let mut data = self.epsv().await?;
+		if offset > 0 {
+			self.expect(format!("REST {}", offset).as_str(), "350").await?;
+		}
+		self.expect(command.as_str(), "150").await?;
+		let mut buffer = vec![0; 64 * 1024];
+		let mut total = 0;
+		loop {
+			let n = data.read(&mut buffer).await?;
+			if n == 0 {
+				break;
+			}
+			if expected.get(total..total + n) != Some(&buffer[..n]) {
+				return Err(Error::other(format!("{}: wrong bytes after {} bytes from {}", command, total, offset)));
+			}
+			total += n;
+		}
+		self.expect_reply(command.as_str(), "226").await?;
+		if total != expected.len() {
+			return Err(Error::other(format!("{}: {} bytes received of {}", command, total, expected.len())));
+		}
+		Ok(total as u64)
+	}
+}
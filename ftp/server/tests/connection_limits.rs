@@ -0,0 +1,92 @@
+/* Copyright 2022 Pierrick MARIE
+
+This file is part of rust-discovery
+
+LCS is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+Rust-discovery is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with rust-discovery.  If not, see <http://www.gnu.org/licenses/>. */
+
+/*
+The limits of max_clients, max_clients_per_ip and max_logins_per_user: a client over a limit gets 421 and is disconnected.
+*/
+
+mod common;
+
+use std::time::Duration;
+
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::net::TcpStream;
+
+use common::{Server, Session, PASSWORD, USER};
+
+/// First line sent by the server to a new connection
+async fn greeting(port: u16) -> String {
+	let mut reader = BufReader::new(TcpStream::connect(("127.0.0.1", port)).await.unwrap());
+	let mut line = String::new();
+	reader.read_line(&mut line).await.unwrap();
+	line
+}
+
+/// The server counts a session until its task ends, a little after the client sees the connection closed
+async fn greeting_after_close(port: u16) -> String {
+	for _ in 0..20 {
+		let line = greeting(port).await;
+		if !line.starts_with("421") {
+			return line;
+		}
+		tokio::time::sleep(Duration::from_millis(50)).await;
+	}
+	greeting(port).await
+}
+
+#[tokio::test]
+async fn max_clients() {
+	let server = Server::start("max-clients", 10, "max_clients = 1").await.unwrap();
+	let session = Session::login(server.port).await.unwrap();
+	assert!(greeting(server.port).await.starts_with("421 Too many connections"));
+	drop(session);
+	assert!(greeting_after_close(server.port).await.starts_with("220"));
+}
+
+#[tokio::test]
+async fn max_clients_per_ip() {
+	let server = Server::start("max-clients-per-ip", 10, "max_clients_per_ip = 2").await.unwrap();
+	let first = Session::login(server.port).await.unwrap();
+	let _second = Session::connect(server.port).await.unwrap();
+	assert!(greeting(server.port).await.starts_with("421 Too many connections"));
+	drop(first);
+	assert!(greeting_after_close(server.port).await.starts_with("220"));
+}
+
+#[tokio::test]
+async fn max_logins_per_user() {
+	let server = Server::start("max-logins", 10, "max_logins_per_user = 1\nmax_clients = 2").await.unwrap();
+	let mut first = Session::login(server.port).await.unwrap();
+
+	let mut second = Session::connect(server.port).await.unwrap();
+	second.expect(format!("USER {}", USER).as_str(), "331").await.unwrap();
+	second.expect(format!("PASS {}", PASSWORD).as_str(), "421 Too many connections").await.unwrap();
+	assert!(second.reply().await.is_err(), "the connection should be closed");
+
+	// The refused session gave its place back: max_clients is not reached
+	assert!(greeting_after_close(server.port).await.starts_with("220"));
+
+	// Once the first session logs out, the user can login again
+	first.expect("QUIT", "221").await.unwrap();
+	for _ in 0..20 {
+		if Session::login(server.port).await.is_ok() {
+			return;
+		}
+		tokio::time::sleep(Duration::from_millis(50)).await;
+	}
+	panic!("the user cannot login after the end of the first session");
+}
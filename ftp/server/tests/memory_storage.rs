@@ -0,0 +1,65 @@
+/* Copyright 2022 Pierrick MARIE
+
+This file is part of rust-discovery
+
+LCS is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+Rust-discovery is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with rust-discovery.  If not, see <http://www.gnu.org/licenses/>. */
+
+/*
+The whole server with storage = "memory": a session uploads, lists, downloads and removes files which never reach the disk.
+*/
+
+mod common;
+
+use common::{Server, Session, BIG_FILE};
+
+const CONTENT: &[u8] = b"first line\nsecond line\n";
+
+fn names(listing: &[u8]) -> Vec<String> {
+	String::from_utf8_lossy(listing).lines().map(|line| line.rsplit(' ').next().unwrap_or_default().to_string()).collect()
+}
+
+#[tokio::test]
+async fn memory_storage_session() {
+	let server = Server::start("memory-storage", 10, "storage = \"memory\"").await.unwrap();
+	let mut session = Session::login(server.port).await.unwrap();
+
+	// The root directory of the user on the disk is not used
+	assert!(names(&session.download("LIST").await.unwrap()).is_empty());
+	session.epsv().await.unwrap();
+	session.expect(format!("RETR {}", BIG_FILE).as_str(), "550").await.unwrap();
+
+	session.stor("notes.txt", CONTENT).await.unwrap();
+	assert!(!server.root().join("notes.txt").exists());
+	assert_eq!(session.download("RETR notes.txt").await.unwrap(), CONTENT);
+	assert_eq!(names(&session.download("LIST").await.unwrap()), vec!["notes.txt"]);
+	session.expect("SIZE notes.txt", "213").await.unwrap();
+
+	// A restart offset cannot be given in ASCII mode, SIZE counts the CRLF end of lines
+	session.expect("TYPE A", "200").await.unwrap();
+	session.expect("SIZE notes.txt", "213 25").await.unwrap();
+	session.expect("REST 11", "504").await.unwrap();
+	session.expect("TYPE I", "200").await.unwrap();
+	session.expect("REST 11", "350").await.unwrap();
+
+	// The files are shared by the sessions of the user
+	let mut other = Session::login(server.port).await.unwrap();
+	assert_eq!(other.download("RETR notes.txt").await.unwrap(), CONTENT);
+
+	session.expect("DELE notes.txt", "250").await.unwrap();
+	assert!(names(&session.download("LIST").await.unwrap()).is_empty());
+	session.expect("DELE notes.txt", "550").await.unwrap();
+
+	session.expect("MKD docs", "257").await.unwrap();
+	session.expect("MKD docs", "550").await.unwrap();
+}
@@ -0,0 +1,133 @@
+/* Copyright 2022 Pierrick MARIE
+
+This file is part of rust-discovery
+
+LCS is free software: you can redistribute it and/or modify
+it under the terms of the GNU General Public License as published by
+the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+Rust-discovery is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU General Public License for more details.
+
+You should have received a copy of the GNU General Public License
+along with rust-discovery.  If not, see <http://www.gnu.org/licenses/>. */
+
+/*
+Uploads with a protected data connection (PROT P, RFC 4217), the control connection is protected with AUTH TLS.
+*/
+
+mod common;
+
+use std::io::{self, Error};
+use std::sync::Arc;
+
+use rustls::pki_types::ServerName;
+use rustls::{ClientConfig, RootCertStore};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::TlsConnector;
+
+use common::{Server, PASSWORD, USER};
+
+/// A control connection protected by AUTH TLS
+struct TlsSession {
+	stream: BufReader<TlsStream<TcpStream>>,
+}
+
+impl TlsSession {
+	async fn login(port: u16, connector: &TlsConnector) -> io::Result<Self> {
+		let mut stream = BufReader::new(TcpStream::connect(("127.0.0.1", port)).await?);
+		reply(&mut stream).await?;
+		stream.write_all(b"AUTH TLS\r\n").await?;
+		if !reply(&mut stream).await?.starts_with("234") {
+			return Err(Error::other("AUTH TLS refused"));
+		}
+		let stream = connector.connect(ServerName::try_from("localhost").map_err(Error::other)?, stream.into_inner()).await?;
+		let mut session = TlsSession { stream: BufReader::new(stream) };
+		session.expect(format!("USER {}", USER).as_str(), "331").await?;
+		session.expect(format!("PASS {}", PASSWORD).as_str(), "230").await?;
+		session.expect("PBSZ 0", "200").await?;
+		Ok(session)
+	}
+
+	async fn command(&mut self, command: &str) -> io::Result<String> {
+		self.stream.write_all(format!("{}\r\n", command).as_bytes()).await?;
+		self.stream.flush().await?;
+		reply(&mut self.stream).await
+	}
+
+	async fn expect(&mut self, command: &str, code: &str) -> io::Result<()> {
+		let reply = self.command(command).await?;
+		if reply.starts_with(code) {
+			Ok(())
+		} else {
+			Err(Error::other(format!("{}: unexpected reply {}", command, reply)))
+		}
+	}
+}
+
+/// Last line of a reply
+async fn reply<R: AsyncBufReadExt + Unpin>(reader: &mut R) -> io::Result<String> {
+	loop {
+		let mut line = String::new();
+		if reader.read_line(&mut line).await? == 0 {
+			return Err(Error::new(io::ErrorKind::UnexpectedEof, "Control connection closed"));
+		}
+		if line.len() > 3 && line.as_bytes()[3] == b' ' {
+			return Ok(line.trim_end().to_string());
+		}
+	}
+}
+
+/// A self-signed certificate for localhost: the configuration of the server, and a connector which trusts it
+fn certificate(directory: &std::path::Path) -> (String, TlsConnector) {
+	let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+	let certificate = directory.join("cert.pem");
+	let key = directory.join("key.pem");
+	std::fs::write(&certificate, certified.cert.pem()).unwrap();
+	std::fs::write(&key, certified.key_pair.serialize_pem()).unwrap();
+
+	let mut roots = RootCertStore::empty();
+	roots.add(certified.cert.der().clone()).unwrap();
+	let config = ClientConfig::builder().with_root_certificates(roots).with_no_client_auth();
+	(format!("tls_certificate = {:?}\ntls_private_key = {:?}", certificate, key), TlsConnector::from(Arc::new(config)))
+}
+
+#[tokio::test]
+async fn failed_data_handshake_keeps_the_session() {
+	let directory = tempfile::tempdir().unwrap();
+	let (config, connector) = certificate(directory.path());
+	let server = Server::start("tls-uploads", 10, config.as_str()).await.unwrap();
+	let mut session = TlsSession::login(server.port, &connector).await.unwrap();
+	session.expect("PROT P", "200").await.unwrap();
+
+	let epsv = session.command("EPSV").await.unwrap();
+	let port = epsv.split("(|||").nth(1).and_then(|end| end.split('|').next()).and_then(|port| port.parse::<u16>().ok()).unwrap();
+	let mut data = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+	session.expect("STOR upload.dat", "150").await.unwrap();
+	// Plain text instead of a TLS handshake
+	data.write_all(b"not a TLS handshake\r\n").await.unwrap();
+	data.shutdown().await.unwrap();
+	assert!(reply(&mut session.stream).await.unwrap().starts_with("425"));
+
+	// The empty file is removed and the session goes on
+	assert!(!server.root().join("upload.dat").exists());
+	session.expect("NOOP", "200").await.unwrap();
+}
+
+#[tokio::test]
+async fn clear_data_connection_is_refused() {
+	let directory = tempfile::tempdir().unwrap();
+	let (config, connector) = certificate(directory.path());
+	let config = format!("{}\nrequire_tls = true", config);
+	let server = Server::start("tls-required", 10, config.as_str()).await.unwrap();
+	let mut session = TlsSession::login(server.port, &connector).await.unwrap();
+
+	// No PROT P: the data connection would not be protected
+	session.expect("STOR upload.dat", "521").await.unwrap();
+	session.expect("PROT C", "534").await.unwrap();
+}